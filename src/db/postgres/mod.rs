@@ -1,4 +1,20 @@
 pub mod postgres_db;
+pub mod postgres_cluster;
+pub mod postgres_credentials;
+pub mod postgres_credential_provider;
+pub mod postgres_listener;
+pub mod postgres_copy;
+pub mod postgres_metrics;
+pub mod postgres_slow_query;
+pub mod postgres_transaction;
+pub mod postgres_shutdown;
+pub mod postgres_session;
+pub mod postgres_tenant;
+pub mod postgres_timeout;
+#[cfg(feature = "tracing")]
+pub mod postgres_tracing;
+#[cfg(test)]
+pub(crate) mod test_support;
 
 pub mod models;
 
@@ -2,8 +2,8 @@ use crate::db::postgres::postgres_db::Database;
 
 use super::model::PostgresModelError;
 
-use std::str::FromStr;
 
+#[allow(dead_code)]
 pub struct Migration {
     name: String,
     executed_at: u64,
@@ -47,7 +47,7 @@ impl MigrationsModel {
             }
             Err(e) => {
                 eprintln!("Database error: {:?}", e);
-                Err(  e.into() )
+                Err(  e )
             }
         }
     }
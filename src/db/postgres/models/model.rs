@@ -41,6 +41,21 @@ pub enum PostgresModelError {
          #[error("PoolError {0:?}")]
     PoolError(deadpool::managed::PoolError<tokio_postgres::Error>),
 
+     #[error("InvalidIdentifier {0:?}")]
+    InvalidIdentifier(String),
+
+     #[error("CopyFailed {0:?}")]
+    CopyFailed(String),
+
+     #[error("InvalidConnectionUrl {0:?}")]
+    InvalidConnectionUrl(String),
+
+     #[error("CredentialsUnavailable {0:?}")]
+    CredentialsUnavailable(String),
+
+     #[error("InvalidStatement {0:?}")]
+    InvalidStatement(String),
+
 
 }
 
@@ -51,6 +66,29 @@ impl PartialEq for PostgresModelError {
 }
 
 impl Eq for PostgresModelError {}
+
+impl PostgresModelError {
+    // Stable name of the variant, e.g. for metrics labels
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Timeout => "Timeout",
+            Self::Postgres(_) => "Postgres",
+            Self::UnexpectedRowsCount => "UnexpectedRowsCount",
+            Self::SerdeJson(_) => "SerdeJson",
+            Self::RowParseError(_) => "RowParseError",
+            Self::ConnectionFailed => "ConnectionFailed",
+            Self::PoolCreationFailed(_) => "PoolCreationFailed",
+            Self::QueryFailed(_) => "QueryFailed",
+            Self::PostgresError(_) => "PostgresError",
+            Self::PoolError(_) => "PoolError",
+            Self::InvalidIdentifier(_) => "InvalidIdentifier",
+            Self::CopyFailed(_) => "CopyFailed",
+            Self::InvalidConnectionUrl(_) => "InvalidConnectionUrl",
+            Self::CredentialsUnavailable(_) => "CredentialsUnavailable",
+            Self::InvalidStatement(_) => "InvalidStatement",
+        }
+    }
+}
  
 
 impl From<deadpool::managed::PoolError<tokio_postgres::Error>> for PostgresModelError {
@@ -0,0 +1,259 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_util::{pin_mut, Stream, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::CopyOutStream;
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::Database;
+use crate::tiny_safe_string::TinySafeString;
+
+/*
+   Bulk load and export with COPY.
+
+        let rows = events.iter().map(|event| -> CopyRow {
+            vec![
+                Box::new(event.contract_address.clone()),
+                Box::new(event.name.clone()),
+                Box::new(event.chain_id),
+            ]
+        });
+
+        let inserted = database
+            .copy_in_binary(
+                "events",
+                &["contract_address", "name", "chain_id"],
+                &[Type::TEXT, Type::TEXT, Type::INT8],
+                rows,
+            )
+            .await?;
+
+        let mut file = tokio::fs::File::create("events.csv").await?;
+        database
+            .copy_out_to_writer("events", &[], CopyFormat::Csv { header: true }, &mut file)
+            .await?;
+*/
+
+// One row of values for `copy_in_binary`, in the same order as the column list
+pub type CopyRow = Vec<Box<dyn ToSql + Sync + Send>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyFormat {
+    Csv { header: bool },
+    Binary,
+}
+
+impl CopyFormat {
+    pub fn build(&self) -> String {
+        match self {
+            Self::Csv { header } => format!("(FORMAT csv, HEADER {})", header),
+            Self::Binary => "(FORMAT binary)".to_string(),
+        }
+    }
+}
+
+// `table (col_a, col_b)`, or just `table` when no columns are given
+fn copy_target(table: &str, columns: &[&str]) -> Result<String, PostgresModelError> {
+    let table = TinySafeString::new(table).map_err(PostgresModelError::InvalidIdentifier)?;
+
+    if columns.is_empty() {
+        return Ok(table.to_string());
+    }
+
+    let columns = columns
+        .iter()
+        .map(|column| TinySafeString::new(column).map_err(PostgresModelError::InvalidIdentifier))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(format!(
+        "{} ({})",
+        table,
+        columns.iter().map(|c| c.as_str()).collect::<Vec<_>>().join(", ")
+    ))
+}
+
+fn copy_in_statement(table: &str, columns: &[&str]) -> Result<String, PostgresModelError> {
+    Ok(format!(
+        "COPY {} FROM STDIN {}",
+        copy_target(table, columns)?,
+        CopyFormat::Binary.build()
+    ))
+}
+
+fn copy_out_statement(table: &str, columns: &[&str], format: CopyFormat) -> Result<String, PostgresModelError> {
+    Ok(format!(
+        "COPY {} TO STDOUT {}",
+        copy_target(table, columns)?,
+        format.build()
+    ))
+}
+
+// The raw COPY output. Holds on to its pooled client until it is dropped.
+pub struct CopyOutByteStream {
+    // Declared before the client so it is dropped first
+    inner: Pin<Box<CopyOutStream>>,
+    _client: deadpool_postgres::Client,
+}
+
+impl Stream for CopyOutByteStream {
+    type Item = Result<Bytes, PostgresModelError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner
+            .as_mut()
+            .poll_next(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map_err(PostgresModelError::from)))
+    }
+}
+
+impl Database {
+    // COPY ... FROM STDIN in binary format. `types` gives the postgres type of each column.
+    // Returns the number of rows written.
+    pub async fn copy_in_binary<I>(
+        &self,
+        table: &str,
+        columns: &[&str],
+        types: &[Type],
+        rows: I,
+    ) -> Result<u64, PostgresModelError>
+    where
+        I: IntoIterator<Item = CopyRow>,
+    {
+        self.copy_in_binary_stream(table, columns, types, futures_util::stream::iter(rows))
+            .await
+    }
+
+    pub async fn copy_in_binary_stream<S>(
+        &self,
+        table: &str,
+        columns: &[&str],
+        types: &[Type],
+        rows: S,
+    ) -> Result<u64, PostgresModelError>
+    where
+        S: Stream<Item = CopyRow>,
+    {
+        let statement = copy_in_statement(table, columns)?;
+
+        if !columns.is_empty() && columns.len() != types.len() {
+            return Err(PostgresModelError::CopyFailed(format!(
+                "{} columns but {} types",
+                columns.len(),
+                types.len()
+            )));
+        }
+
+        let client = self.get_client().await?;
+        let sink = client.copy_in(&statement).await?;
+
+        let writer = BinaryCopyInWriter::new(sink, types);
+        pin_mut!(writer);
+        pin_mut!(rows);
+
+        while let Some(row) = rows.next().await {
+            if row.len() != types.len() {
+                // Dropping the writer without finishing aborts the COPY
+                return Err(PostgresModelError::CopyFailed(format!(
+                    "expected {} values per row but got {}",
+                    types.len(),
+                    row.len()
+                )));
+            }
+
+            let values: Vec<&(dyn ToSql + Sync)> = row
+                .iter()
+                .map(|value| &**value as &(dyn ToSql + Sync))
+                .collect();
+
+            writer.as_mut().write(&values).await?;
+        }
+
+        let written = writer.finish().await?;
+
+        Ok(written)
+    }
+
+    // COPY ... TO STDOUT. An empty column list exports every column.
+    pub async fn copy_out(
+        &self,
+        table: &str,
+        columns: &[&str],
+        format: CopyFormat,
+    ) -> Result<CopyOutByteStream, PostgresModelError> {
+        let statement = copy_out_statement(table, columns, format)?;
+
+        let client = self.get_client().await?;
+        let inner = client.copy_out(&statement).await?;
+
+        Ok(CopyOutByteStream {
+            inner: Box::pin(inner),
+            _client: client,
+        })
+    }
+
+    // Returns the number of bytes written
+    pub async fn copy_out_to_writer<W>(
+        &self,
+        table: &str,
+        columns: &[&str],
+        format: CopyFormat,
+        writer: &mut W,
+    ) -> Result<u64, PostgresModelError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut stream = self.copy_out(table, columns, format).await?;
+        let mut written: u64 = 0;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(|e| PostgresModelError::CopyFailed(e.to_string()))?;
+            written += chunk.len() as u64;
+        }
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| PostgresModelError::CopyFailed(e.to_string()))?;
+
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_in_statement() {
+        assert_eq!(
+            copy_in_statement("events", &["name", "chain_id"]).unwrap(),
+            "COPY events (name, chain_id) FROM STDIN (FORMAT binary)"
+        );
+    }
+
+    #[test]
+    fn test_copy_out_statement() {
+        assert_eq!(
+            copy_out_statement("events", &[], CopyFormat::Csv { header: true }).unwrap(),
+            "COPY events TO STDOUT (FORMAT csv, HEADER true)"
+        );
+        assert_eq!(
+            copy_out_statement("events", &["id"], CopyFormat::Binary).unwrap(),
+            "COPY events (id) TO STDOUT (FORMAT binary)"
+        );
+    }
+
+    #[test]
+    fn test_copy_rejects_unsafe_identifiers() {
+        assert!(copy_in_statement("events; DROP TABLE events", &[]).is_err());
+        assert!(copy_out_statement("events", &["name) TO PROGRAM 'rm -rf /'"], CopyFormat::Binary).is_err());
+    }
+}
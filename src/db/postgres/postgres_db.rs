@@ -1,28 +1,39 @@
-use deadpool_postgres::Timeouts;
 use tokio_postgres::Client;
 use crate::db::postgres::models::model::PostgresModelError;
-use tokio::time::timeout;
+pub use crate::db::postgres::postgres_credentials::DatabaseCredentials;
+use crate::db::postgres::postgres_credential_provider::{CredentialProvider, ProviderConnect};
+use crate::db::postgres::postgres_session::SessionSettings;
+use crate::db::postgres::postgres_shutdown::ConnectionTasks;
+use crate::db::postgres::postgres_metrics::{MetricsRecorder, QueryOperation};
+use crate::db::postgres::postgres_slow_query::SlowQuerySink;
+#[cfg(feature = "tracing")]
+use crate::db::postgres::postgres_tracing;
+use crate::secret_string::{redact_url, SecretString};
+use crate::sql_builder::{BatchInsert, SqlBuilder, UpsertOutcome};
+use futures_util::{Stream, StreamExt};
 use tokio::time::Duration;
-use tokio::time::sleep;
-use log::info;
+use log::{info, warn};
 use tokio;
 use tokio_postgres::{Error as PostgresError, NoTls};
 
 use std::error::Error;
 use tokio_postgres_migration::Migration;
 
-use dotenvy::dotenv;
-use std::env;
-
 use std::fs;
+use std::future::Future;
+use std::pin::Pin;
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
 type MigrationDefinition = (String, String);
 
 #[derive(Clone)]
-struct Migrations {
-    up: Vec<MigrationDefinition>,
-    down: Vec<MigrationDefinition>,
+pub(crate) struct Migrations {
+    pub(crate) up: Vec<MigrationDefinition>,
+    pub(crate) down: Vec<MigrationDefinition>,
 }
 
 pub trait MigrationAsStr {
@@ -31,78 +42,138 @@ pub trait MigrationAsStr {
 
 impl MigrationAsStr for MigrationDefinition {
     fn to_str(&self) -> (&str, &str) {
-        return (self.0.as_str(), self.1.as_str());
+        (self.0.as_str(), self.1.as_str())
     }
 }
 
 pub struct Database {
 
-     pool: deadpool_postgres::Pool, // Or other pool implementation
+     pub(crate) pool: deadpool_postgres::Pool, // Or other pool implementation
 
   //  pub client: Option<  tokio_postgres::Client > ,
     pub migrations_dir_path: Option<String>,
-    pub connection_url:  String  , 
+    pub connection_url: SecretString,
    
     pub max_reconnect_attempts: u32, 
     pub timeout_duration: Duration, 
-}
 
+    // Prepare query/query_one/execute statements once per pooled connection and reuse them
+    pub statement_cache_enabled: bool,
+    // Max statements cached per connection; a connection's cache is cleared when it grows past this
+    pub statement_cache_capacity: usize,
+    statement_cache_counters: StatementCacheCounters,
 
+    pub(crate) metrics: MetricsRecorder,
 
- 
+    // With the `tracing` feature, also record bound parameter values on query spans
+    pub trace_query_parameters: bool,
+
+    // Queries slower than this are logged and sent to `slow_query_sinks`, None turns the log off
+    pub slow_query_threshold: Option<Duration>,
+    // Attach an EXPLAIN plan to slow query events. Costs an extra round trip, meant for dev and CI
+    pub explain_slow_queries: bool,
+    pub slow_query_sinks: Vec<Arc<dyn SlowQuerySink>>,
+
+    // Parsed from connection_url
+    pub(crate) pg_config: tokio_postgres::Config,
+    // Supplies the password for new connections, see `with_credential_provider`
+    pub(crate) credential_provider: Option<Arc<dyn CredentialProvider>>,
+
+    // Connection tasks of the pool and of `connect`, closed by `shutdown`
+    pub(crate) pool_connections: ConnectionTasks,
+    pub(crate) standalone_connections: ConnectionTasks,
+
+    // Init SQL, reset and hooks for pooled connections, see `with_session_init`
+    pub(crate) session: SessionSettings,
+}
 
+#[derive(Default)]
+struct StatementCacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // How many times a connection's cache was cleared for exceeding `statement_cache_capacity`
+    pub evictions: u64,
+}
 
-#[derive(Debug,Clone)]
-pub struct DatabaseCredentials {
-    pub db_name: String,
-    pub db_host: String,
-    pub db_user: String,
-    pub db_password: String,
+// What is being run, for metrics and tracing
+pub(crate) struct QueryContext<'a> {
+    pub(crate) operation: QueryOperation,
+    pub(crate) query: &'a str,
+    pub(crate) params: &'a [&'a (dyn tokio_postgres::types::ToSql + Sync)],
+    // Known when the query was built with SqlBuilder
+    pub(crate) table: Option<&'a str>,
+    // Set by the *_with_timeout methods, nothing after the query may run past it
+    pub(crate) deadline: Option<tokio::time::Instant>,
 }
 
-impl Default for DatabaseCredentials {
-    fn default() -> Self {
+impl<'a> QueryContext<'a> {
+    pub(crate) fn new(
+        operation: QueryOperation,
+        query: &'a str,
+        params: &'a [&'a (dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Self {
         Self {
-            db_name: "postgres".into(),
-            db_host: "localhost".into(),
-            db_user: "postgres".into(),
-            db_password: "postgres".into(),
+            operation,
+            query,
+            params,
+            table: None,
+            deadline: None,
         }
     }
+
+    pub(crate) fn with_table(mut self, table: &'a str) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: tokio::time::Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
 }
 
-impl DatabaseCredentials {
-    pub fn from_env() -> Self {
-        //dotenv().expect(".env file not found"); //need to run this beforehand !! 
+// Number of rows returned or affected by a query, when known up front
+pub(crate) trait RowCount {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    fn row_count(&self) -> Option<u64>;
+}
 
-        Self {
-            db_name: env::var("DB_NAME").unwrap_or("postgres".into()),
-            db_host: env::var("DB_HOST").unwrap_or("localhost".into()),
-            db_user: env::var("DB_USER").unwrap_or("postgres".into()),
-            db_password: env::var("DB_PASSWORD").unwrap_or("postgres".into()),
-        }
+impl RowCount for Vec<tokio_postgres::Row> {
+    fn row_count(&self) -> Option<u64> {
+        Some(self.len() as u64)
     }
+}
 
-    pub fn build_connection_url(&self) -> String {
-        return format!(
-            "postgres://{}:{}@{}/{}",
-            self.db_user, self.db_password, self.db_host, self.db_name
-        );
+impl RowCount for tokio_postgres::Row {
+    fn row_count(&self) -> Option<u64> {
+        Some(1)
     }
 }
 
-enum PostgresInputType {
-    Query,
-    QueryOne,
-    Execute,
+impl RowCount for u64 {
+    fn row_count(&self) -> Option<u64> {
+        Some(*self)
+    }
 }
 
-struct PostgresInput<'a> {
-    input_type: PostgresInputType,
-    query: String,
-    params: &'a [&'a (dyn tokio_postgres::types::ToSql + Sync)],
+impl RowCount for PooledRowStream {
+    fn row_count(&self) -> Option<u64> {
+        None
+    }
 }
 
+
+
+ 
+
+
 impl Database {
     pub fn new(
         conn_url: String, 
@@ -113,8 +184,53 @@ impl Database {
         let config: tokio_postgres::Config = conn_url.parse()
             .map_err(|_e| PostgresModelError::ConnectionFailed )?;
             
+        let pool_connections = ConnectionTasks::default();
+        let pool = Self::build_pool(
+            &config,
+            None,
+            pool_connections.clone(),
+            &SessionSettings::default(),
+            max_pool_connections,
+        )?;
+
+        Ok(Database {
+            pool,
+            migrations_dir_path,
+            connection_url: SecretString::new(conn_url),
+            max_reconnect_attempts: 3,
+            timeout_duration: Duration::from_secs(5),
+            statement_cache_enabled: true,
+            statement_cache_capacity: 256,
+            statement_cache_counters: StatementCacheCounters::default(),
+            metrics: MetricsRecorder::default(),
+            trace_query_parameters: false,
+            slow_query_threshold: None,
+            explain_slow_queries: false,
+            slow_query_sinks: Vec::new(),
+            pg_config: config,
+            credential_provider: None,
+            pool_connections,
+            standalone_connections: ConnectionTasks::default(),
+            session: SessionSettings::default(),
+        })
+    }
+
+    pub(crate) fn build_pool(
+        config: &tokio_postgres::Config,
+        credential_provider: Option<Arc<dyn CredentialProvider>>,
+        connection_tasks: ConnectionTasks,
+        session: &SessionSettings,
+        max_pool_connections: usize,
+    ) -> Result<deadpool_postgres::Pool, PostgresModelError> {
         // Create a manager using the config
-        let manager = deadpool_postgres::Manager::new(config, tokio_postgres::NoTls);
+        let manager = deadpool_postgres::Manager::from_connect(
+            config.clone(),
+            ProviderConnect {
+                provider: credential_provider,
+                tasks: connection_tasks,
+            },
+            deadpool_postgres::ManagerConfig::default(),
+        );
 
 /*
         let deadpool_timeouts = Timeouts {
@@ -122,30 +238,40 @@ impl Database {
             recycle: Some(Duration::from_secs(5)),
             wait: Some(Duration::from_secs(5))
         };  */
-        
+
         // Create the pool with builder pattern
-        let pool = deadpool_postgres::Pool::builder(manager)
-            .max_size( max_pool_connections )
-           // .timeouts( deadpool_timeouts ) 
+        let builder = deadpool_postgres::Pool::builder(manager)
+            .max_size( max_pool_connections );
+           // .timeouts( deadpool_timeouts )
+
+        session
+            .apply_hooks(builder)
             .build()
-            .map_err(|e| PostgresModelError::PoolCreationFailed(e.to_string()))?;
- 
+            .map_err(|e| PostgresModelError::PoolCreationFailed(e.to_string()))
+    }
 
-        
-        Ok(Database {
-            pool,
-            migrations_dir_path,
-            connection_url: conn_url,
-            max_reconnect_attempts: 3,
-            timeout_duration: Duration::from_secs(5)
-        })
+    // Replaces the pool after a setting that the pool is built with changed.
+    // Meant for the `with_*` builders, connections of the old pool are not migrated.
+    pub(crate) fn rebuild_pool(&mut self) -> Result<(), PostgresModelError> {
+        self.pool = Self::build_pool(
+            &self.pg_config,
+            self.credential_provider.clone(),
+            self.pool_connections.clone(),
+            &self.session,
+            self.pool.status().max_size,
+        )?;
+
+        Ok(())
     }
 }
 
 
 impl Database {
 
-   
+    // The connection url with its password replaced, safe to log
+    pub fn redacted_url(&self) -> String {
+        redact_url(self.connection_url.expose_secret())
+    }
 
     pub async fn connect(
        // credentials: DatabaseCredentials,
@@ -154,18 +280,27 @@ impl Database {
         // Define the connection URL.
        // let conn_url = credentials.build_connection_url();
 
-        info!("Connecting to db: {}", self.connection_url);
+        info!("Connecting to db: {}", self.redacted_url());
 
-        let (client, connection) = tokio_postgres::connect(&self.connection_url, NoTls).await?;
+        let pg_config = match self.connection_config().await {
+            Ok(pg_config) => pg_config,
+            Err(e) => {
+                warn!("Credential provider failed, using the connection url password: {}", e);
+                self.pg_config.clone()
+            }
+        };
+
+        let (client, connection) = pg_config.connect(NoTls).await?;
 
         // The connection object performs the actual communication with the database,
         // so spawn it off to run on its own.
-        tokio::spawn(async move {
+        let connection_task = tokio::spawn(async move {
             //this is a blocking call i think !!!
             if let Err(e) = connection.await {
                 eprintln!("postgres connection error: {}", e);
             }
         });
+        self.standalone_connections.register(connection_task.abort_handle());
 
      //   self.client = Some(client);
 
@@ -174,7 +309,7 @@ impl Database {
 
  
 
-    fn read_migration_files(migrations_dir_path: Option<String>) -> Migrations {
+    pub(crate) fn read_migration_files(migrations_dir_path: Option<String>) -> Migrations {
         let mut migrations = Migrations {
             up: Vec::new(),
             down: Vec::new(),
@@ -222,7 +357,7 @@ impl Database {
         migrations.down.sort_by(|a, b| b.0.cmp(&a.0));
                 
 
-        return migrations;
+        migrations
     }
 
     pub async fn migrate(&mut self) -> Result<(), Box<dyn Error>> {
@@ -239,6 +374,9 @@ impl Database {
             migration.up(client, &[up_migration.to_str()]).await?;
         }
 
+        // Statements prepared against the old schema may no longer be valid
+        self.clear_statement_cache();
+
         // ...
         Ok(())
     }
@@ -264,6 +402,8 @@ impl Database {
             migration.down(client, &[down_migration.to_str()]).await?;
         }
 
+        self.clear_statement_cache();
+
         Ok(())
     }
 
@@ -272,20 +412,8 @@ impl Database {
         query: &str,
         params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
     ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
-
-        /*let client = &self.connect().await?;
-
-        let rows = client.query(query, params).await?;
-        Ok(rows)*/
-
-         // Get a client from the pool
-        let client = self.pool.get().await?;
-        
-        // Execute the query and let the client be dropped automatically afterward
-        let rows = client.query(query, params).await?;
-        
-        Ok(rows)
-
+        self.pooled_query(QueryContext::new(QueryOperation::Query, query, params))
+            .await
     } 
  
 
@@ -294,21 +422,8 @@ impl Database {
         query: &str,
         params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
     ) -> Result<tokio_postgres::Row, PostgresModelError> {
-            /*
-          let client = &self.connect().await?;
-
-        let rows =  client.query_one(query, params).await?;
-        Ok(rows)
-
-        */
-
-
-         let client = self.pool.get().await ?;
-        
-        // Execute the query and let the client be dropped automatically afterward
-        let row = client.query_one(query, params).await?;
-        
-        Ok(row)
+        self.pooled_query_one(QueryContext::new(QueryOperation::QueryOne, query, params))
+            .await
     }
  
     //use for insert, update, etc
@@ -317,27 +432,358 @@ impl Database {
         query: &str,
         params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
     ) -> Result<u64, PostgresModelError> {
-        /*
-          let client = &self.connect().await?;
+        self.pooled_execute(QueryContext::new(QueryOperation::Execute, query, params))
+            .await
+    }   
 
-        
+    pub async fn query_sql_builder(
+        &self,
+        sql_builder: &SqlBuilder,
+    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
+        let (query, params) = sql_builder.build()?;
 
-        let rows = client.execute(query, params).await?;
-        Ok(rows)*/
+        let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
 
+        self.pooled_query(
+            QueryContext::new(QueryOperation::Query, &query, &built_params)
+                .with_table(&sql_builder.table_name),
+        )
+        .await
+    }
+
+    pub async fn query_one_sql_builder(
+        &self,
+        sql_builder: &SqlBuilder,
+    ) -> Result<tokio_postgres::Row, PostgresModelError> {
+        let (query, params) = sql_builder.build()?;
+
+        let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
+
+        self.pooled_query_one(
+            QueryContext::new(QueryOperation::QueryOne, &query, &built_params)
+                .with_table(&sql_builder.table_name),
+        )
+        .await
+    }
+
+    // For inserts with an ON CONFLICT clause
+    pub async fn upsert_sql_builder(
+        &self,
+        sql_builder: &SqlBuilder,
+    ) -> Result<UpsertOutcome, PostgresModelError> {
+        if sql_builder.on_conflict.is_none() {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "upsert into {} has no ON CONFLICT clause",
+                sql_builder.table_name
+            )));
+        }
+
+        let mut rows = self.query_sql_builder(sql_builder).await?;
+        if rows.len() > 1 {
+            return Err(PostgresModelError::UnexpectedRowsCount);
+        }
+
+        UpsertOutcome::from_row(rows.pop())
+    }
+
+    // Runs every chunk of the batch in one transaction, returns the number of rows inserted
+    pub async fn execute_batch_insert(
+        &self,
+        batch: &BatchInsert,
+    ) -> Result<u64, PostgresModelError> {
+        let statements = batch.build()?;
+        if statements.is_empty() {
+            return Ok(0);
+        }
+
+        let transaction = self.transaction().await?;
+        let mut inserted = 0;
+
+        for (query, params) in &statements {
+            let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
+
+            match transaction.execute(query, &built_params).await {
+                Ok(rows) => inserted += rows,
+                Err(e) => {
+                    let _ = transaction.rollback().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        transaction.commit().await?;
+
+        Ok(inserted)
+    }
+
+    pub async fn execute_sql_builder(
+        &self,
+        sql_builder: &SqlBuilder,
+    ) -> Result<u64, PostgresModelError> {
+        let (query, params) = sql_builder.build()?;
+
+        let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
+
+        self.pooled_execute(
+            QueryContext::new(QueryOperation::Execute, &query, &built_params)
+                .with_table(&sql_builder.table_name),
+        )
+        .await
+    }
+
+    async fn pooled_query(
+        &self,
+        context: QueryContext<'_>,
+    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
+        self.instrumented(&context, async {
+            // Get a client from the pool and let it be dropped automatically afterward
+            let client = self.pool.get().await?;
+
+            self.query_on(&client, context.query, context.params).await
+        })
+        .await
+    }
+
+    async fn pooled_query_one(
+        &self,
+        context: QueryContext<'_>,
+    ) -> Result<tokio_postgres::Row, PostgresModelError> {
+        self.instrumented(&context, async {
+            let client = self.pool.get().await?;
+
+            self.query_one_on(&client, context.query, context.params).await
+        })
+        .await
+    }
+
+    async fn pooled_execute(&self, context: QueryContext<'_>) -> Result<u64, PostgresModelError> {
+        self.instrumented(&context, async {
+            let client = self.pool.get().await?;
+
+            self.execute_on(&client, context.query, context.params).await
+        })
+        .await
+    }
+
+    pub(crate) async fn query_on(
+        &self,
+        client: &deadpool_postgres::Client,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
+        let rows = match self.cached_statement(client, query).await? {
+            Some(statement) => client.query(&statement, params).await?,
+            None => client.query(query, params).await?,
+        };
+
+        Ok(rows)
+    }
+
+    pub(crate) async fn query_one_on(
+        &self,
+        client: &deadpool_postgres::Client,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<tokio_postgres::Row, PostgresModelError> {
+        let row = match self.cached_statement(client, query).await? {
+            Some(statement) => client.query_one(&statement, params).await?,
+            None => client.query_one(query, params).await?,
+        };
+
+        Ok(row)
+    }
+
+    pub(crate) async fn execute_on(
+        &self,
+        client: &deadpool_postgres::Client,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<u64, PostgresModelError> {
+        let count = match self.cached_statement(client, query).await? {
+            Some(statement) => client.execute(&statement, params).await?,
+            None => client.execute(query, params).await?,
+        };
 
-         // Get a client from the pool
-        let client = self.pool.get().await?;
-        
-        // Execute the query and let the client be dropped automatically afterward
-        let count = client.execute(query, params).await?;
-        
         Ok(count)
+    }
+
+    // Runs a query, recording its duration and outcome in `metrics()` (and a span with the `tracing` feature).
+    // The connection must be released by the time `run` finishes, slow queries are explained on a new one.
+    pub(crate) async fn instrumented<T, F>(
+        &self,
+        context: &QueryContext<'_>,
+        run: F,
+    ) -> Result<T, PostgresModelError>
+    where
+        T: RowCount,
+        F: Future<Output = Result<T, PostgresModelError>>,
+    {
+        self.instrument(context, None, run).await
+    }
 
+    // Like `instrumented`, for a query run on a client the caller keeps holding (a transaction, a tenant).
+    // Slow queries are explained on that same client.
+    pub(crate) async fn instrumented_on<T, F>(
+        &self,
+        client: &deadpool_postgres::Client,
+        context: &QueryContext<'_>,
+        run: F,
+    ) -> Result<T, PostgresModelError>
+    where
+        T: RowCount,
+        F: Future<Output = Result<T, PostgresModelError>>,
+    {
+        self.instrument(context, Some(client), run).await
+    }
 
-    }   
+    async fn instrument<T, F>(
+        &self,
+        context: &QueryContext<'_>,
+        client: Option<&deadpool_postgres::Client>,
+        run: F,
+    ) -> Result<T, PostgresModelError>
+    where
+        T: RowCount,
+        F: Future<Output = Result<T, PostgresModelError>>,
+    {
+        let started = Instant::now();
+
+        #[cfg(feature = "tracing")]
+        let span = postgres_tracing::query_span(self, context);
+
+        #[cfg(feature = "tracing")]
+        let result = tracing::Instrument::instrument(run, span.clone()).await;
+
+        #[cfg(not(feature = "tracing"))]
+        let result = run.await;
 
+        let elapsed = started.elapsed();
+
+        #[cfg(feature = "tracing")]
+        postgres_tracing::record_outcome(&span, elapsed, &result);
+
+        self.metrics
+            .record(context.operation, elapsed, result.as_ref().err());
+
+        if self.is_slow(elapsed) {
+            self.report_slow_query(context, client, elapsed, result.as_ref().err())
+                .await;
+        }
 
+        result
+    }
+
+    // Like `query`, but yields rows as they arrive instead of collecting them all into memory.
+    // The pooled client is held until the stream is dropped.
+    pub async fn query_stream(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<PooledRowStream, PostgresModelError> {
+        self.pooled_query_stream(QueryContext::new(QueryOperation::QueryStream, query, params))
+            .await
+    }
+
+    async fn pooled_query_stream(
+        &self,
+        context: QueryContext<'_>,
+    ) -> Result<PooledRowStream, PostgresModelError> {
+        // Only the time until the first response is recorded, not the time spent reading the stream.
+        // The stream keeps its client past `instrumented`, so slow streams are never explained.
+        self.instrumented(&context, async {
+            let client = self.pool.get().await?;
+
+            let inner = match self.cached_statement(&client, context.query).await? {
+                Some(statement) => client.query_raw(&statement, context.params.iter().copied()).await?,
+                None => client.query_raw(context.query, context.params.iter().copied()).await?,
+            };
+
+            Ok(PooledRowStream {
+                inner: Box::pin(inner),
+                _client: client,
+            })
+        })
+        .await
+    }
+
+    // Streams rows mapped through a model's `from_row`
+    pub async fn query_stream_as<T, F>(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+        from_row: F,
+    ) -> Result<impl Stream<Item = Result<T, PostgresModelError>>, PostgresModelError>
+    where
+        F: FnMut(&tokio_postgres::Row) -> Result<T, PostgresModelError>,
+    {
+        let stream = self.query_stream(query, params).await?;
+
+        Ok(map_rows(stream, from_row))
+    }
+
+    pub async fn query_stream_sql_builder(
+        &self,
+        sql_builder: &SqlBuilder,
+    ) -> Result<PooledRowStream, PostgresModelError> {
+        let (query, params) = sql_builder.build()?;
+
+        let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
+
+        self.pooled_query_stream(
+            QueryContext::new(QueryOperation::QueryStream, &query, &built_params)
+                .with_table(&sql_builder.table_name),
+        )
+        .await
+    }
+
+    // Prepares through the connection's statement cache, or returns None when the cache is disabled
+    async fn cached_statement(
+        &self,
+        client: &deadpool_postgres::Client,
+        query: &str,
+    ) -> Result<Option<tokio_postgres::Statement>, PostgresModelError> {
+        if !self.statement_cache_enabled {
+            return Ok(None);
+        }
+
+        let cached_before = client.statement_cache.size();
+        let statement = client.prepare_cached(query).await?;
+
+        // The client is ours alone, so the cache only grows if this was a miss
+        if client.statement_cache.size() > cached_before {
+            self.statement_cache_counters.misses.fetch_add(1, Ordering::Relaxed);
+
+            if client.statement_cache.size() > self.statement_cache_capacity {
+                client.statement_cache.clear();
+                self.statement_cache_counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        } else {
+            self.statement_cache_counters.hits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(Some(statement))
+    }
+
+    // Drop every cached statement on every pooled connection, e.g. after a migration changed the schema
+    pub fn clear_statement_cache(&self) {
+        self.pool.manager().statement_caches.clear();
+    }
+
+    pub fn statement_cache_stats(&self) -> StatementCacheStats {
+        StatementCacheStats {
+            hits: self.statement_cache_counters.hits.load(Ordering::Relaxed),
+            misses: self.statement_cache_counters.misses.load(Ordering::Relaxed),
+            evictions: self.statement_cache_counters.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    // Borrow a client from the pool, e.g. to run a transaction.
+    // The client goes back to the pool when it is dropped.
+    pub async fn get_client(&self) -> Result<deadpool_postgres::Client, PostgresModelError> {
+        let client = self.pool.get().await?;
+
+        Ok(client)
+    }
 
     pub async fn check_connection(&self) -> Result<bool, PostgresModelError> {
     // Get a client from the pool
@@ -372,17 +818,6 @@ impl Database {
     }
 
          
-     pub async fn query_with_timeout(
-        &self,
-        query: &str,
-        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
-    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
-        match timeout(self.timeout_duration, self.query(query, params)).await {
-            Ok(result) => result,
-            Err(_) => Err(PostgresModelError::ConnectionFailed) // Timeout occurred
-        }
-    }
-
     pub async fn query_with_retry(
         &self,
         query: &str,
@@ -416,12 +851,139 @@ impl Database {
  
 }
 
+// Rows from `Database::query_stream`. Holds on to its pooled client until it is dropped.
+pub struct PooledRowStream {
+    // Declared before the client so it is dropped first
+    inner: Pin<Box<tokio_postgres::RowStream>>,
+    _client: deadpool_postgres::Client,
+}
+
+impl PooledRowStream {
+    // Only known once the stream has been read to the end
+    pub fn rows_affected(&self) -> Option<u64> {
+        self.inner.rows_affected()
+    }
+}
+
+impl Stream for PooledRowStream {
+    type Item = Result<tokio_postgres::Row, PostgresModelError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner
+            .as_mut()
+            .poll_next(cx)
+            .map(|row| row.map(|row| row.map_err(PostgresModelError::from)))
+    }
+}
+
+fn map_rows<T, F>(
+    stream: PooledRowStream,
+    mut from_row: F,
+) -> impl Stream<Item = Result<T, PostgresModelError>>
+where
+    F: FnMut(&tokio_postgres::Row) -> Result<T, PostgresModelError>,
+{
+    stream.map(move |row| row.and_then(|row| from_row(&row)))
+}
+
 pub fn try_get_option<'a, T: tokio_postgres::types::FromSql<'a>>(
     row: &'a tokio_postgres::Row,
     column: &str,
 ) -> Option<T> {
-    match row.try_get::<&str, T>(column) {
-        Ok(value) => Some(value),
-        Err(_) => None,
+    row.try_get::<&str, T>(column).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::postgres::test_support::test_database;
+
+    use tokio::time::Duration;
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_stream_holds_connection_until_dropped() {
+        let database = test_database();
+
+        let mut stream = database
+            .query_stream("SELECT generate_series(1, 3)", &[])
+            .await
+            .unwrap();
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.get::<_, i32>(0), 1);
+
+        // The only connection is still held by the half-read stream
+        assert_eq!(database.pool.status().available, 0);
+        let checkout = tokio::time::timeout(Duration::from_millis(100), database.pool.get()).await;
+        assert!(checkout.is_err());
+
+        drop(stream);
+        assert_eq!(database.pool.status().available, 1);
+
+        let rows: Vec<_> = database
+            .query_stream("SELECT generate_series(1, 3)", &[])
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(database.pool.status().available, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_statement_cache_counters() {
+        let mut database = test_database();
+        database.statement_cache_capacity = 2;
+
+        database.query("SELECT 1", &[]).await.unwrap();
+        database.query("SELECT 1", &[]).await.unwrap();
+        assert_eq!(
+            database.statement_cache_stats(),
+            StatementCacheStats { hits: 1, misses: 1, evictions: 0 }
+        );
+
+        // A third statement goes over capacity and the connection's cache is cleared
+        database.query("SELECT 2", &[]).await.unwrap();
+        database.query("SELECT 3", &[]).await.unwrap();
+        assert_eq!(
+            database.statement_cache_stats(),
+            StatementCacheStats { hits: 1, misses: 3, evictions: 1 }
+        );
+
+        database.query("SELECT 1", &[]).await.unwrap();
+        assert_eq!(database.statement_cache_stats().misses, 4);
+
+        database.query("SELECT 1", &[]).await.unwrap();
+        assert_eq!(database.statement_cache_stats().hits, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_clear_statement_cache() {
+        let database = test_database();
+
+        database.query("SELECT 1", &[]).await.unwrap();
+        database.query("SELECT 1", &[]).await.unwrap();
+        assert_eq!(database.statement_cache_stats().hits, 1);
+
+        database.clear_statement_cache();
+
+        database.query("SELECT 1", &[]).await.unwrap();
+        assert_eq!(
+            database.statement_cache_stats(),
+            StatementCacheStats { hits: 1, misses: 2, evictions: 0 }
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_disabled_statement_cache_counts_nothing() {
+        let mut database = test_database();
+        database.statement_cache_enabled = false;
+
+        database.query("SELECT 1", &[]).await.unwrap();
+        database.query("SELECT 1", &[]).await.unwrap();
+        assert_eq!(database.statement_cache_stats(), StatementCacheStats::default());
     }
 }
@@ -0,0 +1,307 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::{Stream, StreamExt};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_credential_provider::{resolve_config, CredentialProvider};
+use crate::db::postgres::postgres_db::Database;
+use crate::db::postgres::postgres_shutdown::ConnectionTasks;
+use crate::tiny_safe_string::TinySafeString;
+
+/*
+   LISTEN/NOTIFY on a dedicated connection (not taken from the pool).
+
+        let mut payments = database.listen(&["payments_inserted"]).await?.json::<PaymentInserted>();
+
+        while let Some(payment) = payments.next().await {
+            let payment = payment?;
+            ...
+        }
+
+        // somewhere else
+        database.notify_json("payments_inserted", &payment_inserted).await?;
+
+   If the connection drops, the listener reconnects and issues LISTEN again.
+   Notifications sent while it was disconnected are lost.
+*/
+
+// How many notifications may queue up before we stop reading from the connection
+const NOTIFICATION_BUFFER_SIZE: usize = 1024;
+
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub process_id: i32,
+    pub channel: String,
+    pub payload: String,
+}
+
+impl Notification {
+    pub fn payload_json<T: DeserializeOwned>(&self) -> Result<T, PostgresModelError> {
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+impl From<tokio_postgres::Notification> for Notification {
+    fn from(notification: tokio_postgres::Notification) -> Self {
+        Self {
+            process_id: notification.process_id(),
+            channel: notification.channel().to_string(),
+            payload: notification.payload().to_string(),
+        }
+    }
+}
+
+pub struct NotificationStream {
+    receiver: mpsc::Receiver<Notification>,
+    listener: JoinHandle<()>,
+}
+
+impl NotificationStream {
+    // Deserialize each notification payload as JSON
+    pub fn json<T: DeserializeOwned>(self) -> impl Stream<Item = Result<T, PostgresModelError>> {
+        self.map(|notification| notification.payload_json())
+    }
+}
+
+impl Stream for NotificationStream {
+    type Item = Notification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for NotificationStream {
+    fn drop(&mut self) {
+        // Closes the LISTEN connection
+        self.listener.abort();
+    }
+}
+
+fn parse_channels(channels: &[&str]) -> Result<Vec<TinySafeString>, PostgresModelError> {
+    channels
+        .iter()
+        .map(|channel| TinySafeString::new(channel).map_err(PostgresModelError::InvalidIdentifier))
+        .collect()
+}
+
+fn reconnect_backoff(attempts: u32) -> Duration {
+    Duration::from_millis(100_u64.saturating_mul(2_u64.saturating_pow(attempts))).min(MAX_RECONNECT_BACKOFF)
+}
+
+struct ListenerState {
+    // Reports the outcome of the very first LISTEN back to `Database::listen`
+    ready: Option<oneshot::Sender<Result<(), PostgresModelError>>>,
+    attempts: u32,
+}
+
+impl ListenerState {
+    fn subscribed(&mut self) {
+        self.attempts = 0;
+        if let Some(ready) = self.ready.take() {
+            let _ = ready.send(Ok(()));
+        }
+    }
+}
+
+async fn run_listener(
+    pg_config: tokio_postgres::Config,
+    credential_provider: Option<Arc<dyn CredentialProvider>>,
+    channels: Vec<TinySafeString>,
+    sender: mpsc::Sender<Notification>,
+    ready: oneshot::Sender<Result<(), PostgresModelError>>,
+    connection_tasks: ConnectionTasks,
+) {
+    let mut state = ListenerState {
+        ready: Some(ready),
+        attempts: 0,
+    };
+
+    loop {
+        let listening = listen_until_disconnected(&pg_config, credential_provider.as_ref(), &channels, &sender, &mut state);
+
+        let error = tokio::select! {
+            result = listening => match result {
+                // The stream was dropped
+                Ok(()) => return,
+                Err(e) => e,
+            },
+            // The database is shutting down, dropping the connection closes it
+            _ = connection_tasks.closed() => return,
+        };
+
+        // Never managed to subscribe, so let the caller see the error instead of retrying forever
+        if let Some(ready) = state.ready.take() {
+            let _ = ready.send(Err(error));
+            return;
+        }
+
+        state.attempts = state.attempts.saturating_add(1);
+        let backoff = reconnect_backoff(state.attempts);
+        warn!("postgres LISTEN connection lost: {}. Reconnecting in {:?}", error, backoff);
+
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = sender.closed() => return,
+            _ = connection_tasks.closed() => return,
+        }
+    }
+}
+
+async fn listen_until_disconnected(
+    pg_config: &tokio_postgres::Config,
+    credential_provider: Option<&Arc<dyn CredentialProvider>>,
+    channels: &[TinySafeString],
+    sender: &mpsc::Sender<Notification>,
+    state: &mut ListenerState,
+) -> Result<(), PostgresModelError> {
+    // Resolved on every reconnect so a rotated password is picked up
+    let pg_config = resolve_config(pg_config, credential_provider).await?;
+    let (client, mut connection) = pg_config.connect(NoTls).await?;
+
+    // Drive the connection ourselves so that notifications are not thrown away
+    let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+
+    let listen_sql = channels
+        .iter()
+        .map(|channel| format!("LISTEN \"{}\";", channel))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    {
+        let listen = client.batch_execute(&listen_sql);
+        tokio::pin!(listen);
+
+        loop {
+            tokio::select! {
+                result = &mut listen => {
+                    result?;
+                    break;
+                }
+                message = messages.next() => match message {
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err(PostgresModelError::ConnectionFailed),
+                },
+            }
+        }
+    }
+
+    state.subscribed();
+
+    loop {
+        tokio::select! {
+            message = messages.next() => match message {
+                Some(Ok(AsyncMessage::Notification(notification))) => {
+                    if sender.send(notification.into()).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(PostgresModelError::ConnectionFailed),
+            },
+            _ = sender.closed() => return Ok(()),
+        }
+    }
+}
+
+impl Database {
+    // Subscribe to one or more channels. Channel names must be alphanumeric/underscore.
+    pub async fn listen(&self, channels: &[&str]) -> Result<NotificationStream, PostgresModelError> {
+        let channels = parse_channels(channels)?;
+
+        let (sender, receiver) = mpsc::channel(NOTIFICATION_BUFFER_SIZE);
+        let (ready_sender, ready_receiver) = oneshot::channel();
+
+        let listener = tokio::spawn(run_listener(
+            self.pg_config.clone(),
+            self.credential_provider.clone(),
+            channels,
+            sender,
+            ready_sender,
+            self.standalone_connections.clone(),
+        ));
+        // So `shutdown` waits for it and closes it by force if it has to
+        self.standalone_connections.register(listener.abort_handle());
+
+        match ready_receiver.await {
+            Ok(Ok(())) => Ok(NotificationStream { receiver, listener }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(PostgresModelError::ConnectionFailed),
+        }
+    }
+
+    pub async fn notify(&self, channel: &str, payload: &str) -> Result<(), PostgresModelError> {
+        let channel = TinySafeString::new(channel).map_err(PostgresModelError::InvalidIdentifier)?;
+
+        self.execute("SELECT pg_notify($1, $2)", &[&channel.as_str(), &payload])
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn notify_json<T: Serialize>(&self, channel: &str, payload: &T) -> Result<(), PostgresModelError> {
+        let payload = serde_json::to_string(payload)?;
+
+        self.notify(channel, &payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::postgres::test_support::test_database;
+
+    #[test]
+    fn test_parse_channels_rejects_unsafe_names() {
+        assert!(parse_channels(&["payments_inserted", "webhooks"]).is_ok());
+        assert!(parse_channels(&["payments; DROP TABLE payments"]).is_err());
+        assert!(parse_channels(&["bad\"quote"]).is_err());
+    }
+
+    #[test]
+    fn test_reconnect_backoff_is_capped() {
+        assert_eq!(reconnect_backoff(0), Duration::from_millis(100));
+        assert_eq!(reconnect_backoff(3), Duration::from_millis(800));
+        assert_eq!(reconnect_backoff(100), MAX_RECONNECT_BACKOFF);
+    }
+
+    #[test]
+    fn test_notification_payload_json() {
+        let notification = Notification {
+            process_id: 1,
+            channel: "payments_inserted".into(),
+            payload: "{\"id\": 7}".into(),
+        };
+
+        let payload: serde_json::Value = notification.payload_json().unwrap();
+        assert_eq!(payload["id"], 7);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_shutdown_closes_listener() {
+        let database = test_database();
+
+        let mut notifications = database.listen(&["shutdown_test"]).await.unwrap();
+
+        let report = database.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(report.closed, 1);
+        assert_eq!(report.force_closed, 0);
+        assert!(report.elapsed < Duration::from_secs(5));
+
+        assert!(notifications.next().await.is_none());
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tokio::time::Duration;
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::{Database, StatementCacheStats};
+
+/*
+   Pool status and query metrics for a `Database`.
+
+        let metrics = database.metrics();
+        if metrics.pool.waiting > 0 {
+            warn!("{} requests are waiting for a postgres connection", metrics.pool.waiting);
+        }
+
+   With the `metrics` cargo feature the same numbers are also reported through the
+   `metrics` crate facade, so any exporter (Prometheus, OpenTelemetry, ...) can pick them up:
+
+        degen_sql_queries_total{operation}
+        degen_sql_query_errors_total{operation, error}
+        degen_sql_query_duration_seconds{operation}
+        degen_sql_pool_max_size, degen_sql_pool_size, degen_sql_pool_available, degen_sql_pool_waiting
+*/
+
+// Upper bounds of the latency histogram buckets, in milliseconds
+const LATENCY_BUCKETS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryOperation {
+    Query,
+    QueryOne,
+    Execute,
+    QueryStream,
+}
+
+impl QueryOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Query => "query",
+            Self::QueryOne => "query_one",
+            Self::Execute => "execute",
+            Self::QueryStream => "query_stream",
+        }
+    }
+
+    const ALL: [QueryOperation; 4] = [Self::Query, Self::QueryOne, Self::Execute, Self::QueryStream];
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub max_size: usize,
+    // Connections currently open, idle or in use
+    pub size: usize,
+    // Idle connections ready to be handed out
+    pub available: usize,
+    // Callers waiting for a connection
+    pub waiting: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyBucket {
+    // None is the +Inf bucket
+    pub le: Option<Duration>,
+    // Cumulative, like a Prometheus histogram
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: Vec<LatencyBucket>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        Some(Duration::from_nanos((self.sum.as_nanos() / self.count as u128) as u64))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseMetrics {
+    pub pool: PoolStatus,
+    // Keyed by operation: query, query_one, execute, query_stream
+    pub queries: BTreeMap<String, u64>,
+    // Keyed by PostgresModelError variant
+    pub errors: BTreeMap<String, u64>,
+    pub latency: LatencyHistogram,
+    pub statement_cache: StatementCacheStats,
+}
+
+#[derive(Default)]
+pub(crate) struct MetricsRecorder {
+    queries: [AtomicU64; 4],
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    // One counter per bucket in LATENCY_BUCKETS_MS plus the +Inf bucket (not cumulative)
+    latency_buckets: [AtomicU64; 13],
+    latency_sum_micros: AtomicU64,
+}
+
+impl MetricsRecorder {
+    pub(crate) fn record(
+        &self,
+        operation: QueryOperation,
+        elapsed: Duration,
+        error: Option<&PostgresModelError>,
+    ) {
+        self.queries[operation.index()].fetch_add(1, Ordering::Relaxed);
+
+        // Compared as a Duration, whole milliseconds would put 1.5ms in the 1ms bucket
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|le| elapsed <= Duration::from_millis(*le))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+        if let Some(error) = error {
+            let mut errors = self.errors.lock().unwrap();
+            *errors.entry(error.variant_name()).or_insert(0) += 1;
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            let operation = operation.as_str();
+
+            metrics::counter!("degen_sql_queries_total", "operation" => operation).increment(1);
+            metrics::histogram!("degen_sql_query_duration_seconds", "operation" => operation)
+                .record(elapsed.as_secs_f64());
+
+            if let Some(error) = error {
+                metrics::counter!(
+                    "degen_sql_query_errors_total",
+                    "operation" => operation,
+                    "error" => error.variant_name()
+                )
+                .increment(1);
+            }
+        }
+    }
+
+    fn latency(&self) -> LatencyHistogram {
+        let mut cumulative = 0;
+        let mut buckets = Vec::with_capacity(self.latency_buckets.len());
+
+        for (index, counter) in self.latency_buckets.iter().enumerate() {
+            cumulative += counter.load(Ordering::Relaxed);
+            buckets.push(LatencyBucket {
+                le: LATENCY_BUCKETS_MS.get(index).map(|ms| Duration::from_millis(*ms)),
+                count: cumulative,
+            });
+        }
+
+        LatencyHistogram {
+            buckets,
+            count: cumulative,
+            sum: Duration::from_micros(self.latency_sum_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn queries(&self) -> BTreeMap<String, u64> {
+        QueryOperation::ALL
+            .iter()
+            .map(|operation| {
+                (
+                    operation.as_str().to_string(),
+                    self.queries[operation.index()].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    fn errors(&self) -> BTreeMap<String, u64> {
+        self.errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(variant, count)| (variant.to_string(), *count))
+            .collect()
+    }
+}
+
+impl Database {
+    pub fn pool_status(&self) -> PoolStatus {
+        let status = self.pool.status();
+
+        PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+        }
+    }
+
+    pub fn metrics(&self) -> DatabaseMetrics {
+        DatabaseMetrics {
+            pool: self.pool_status(),
+            queries: self.metrics.queries(),
+            errors: self.metrics.errors(),
+            latency: self.metrics.latency(),
+            statement_cache: self.statement_cache_stats(),
+        }
+    }
+
+    // Push the current pool status to the `metrics` gauges. Call this periodically,
+    // e.g. right before your exporter is scraped.
+    #[cfg(feature = "metrics")]
+    pub fn publish_pool_metrics(&self) {
+        let pool = self.pool_status();
+
+        metrics::gauge!("degen_sql_pool_max_size").set(pool.max_size as f64);
+        metrics::gauge!("degen_sql_pool_size").set(pool.size as f64);
+        metrics::gauge!("degen_sql_pool_available").set(pool.available as f64);
+        metrics::gauge!("degen_sql_pool_waiting").set(pool.waiting as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_buckets_are_cumulative() {
+        let recorder = MetricsRecorder::default();
+
+        recorder.record(QueryOperation::Query, Duration::from_micros(500), None);
+        recorder.record(QueryOperation::Query, Duration::from_millis(30), None);
+        recorder.record(QueryOperation::Execute, Duration::from_secs(60), None);
+
+        let latency = recorder.latency();
+        assert_eq!(latency.count, 3);
+        assert_eq!(latency.buckets[0].le, Some(Duration::from_millis(1)));
+        assert_eq!(latency.buckets[0].count, 1);
+        // 30ms falls in the 50ms bucket
+        assert_eq!(latency.buckets[4].count, 2);
+        assert_eq!(latency.buckets.last().unwrap().le, None);
+        assert_eq!(latency.buckets.last().unwrap().count, 3);
+    }
+
+    #[test]
+    fn test_bucket_bounds_are_not_truncated() {
+        let recorder = MetricsRecorder::default();
+
+        recorder.record(QueryOperation::Query, Duration::from_millis(1), None);
+        recorder.record(QueryOperation::Query, Duration::from_micros(1500), None);
+
+        let latency = recorder.latency();
+        // 1ms is inclusive, 1.5ms is over it
+        assert_eq!(latency.buckets[0].count, 1);
+        assert_eq!(latency.buckets[1].count, 2);
+    }
+
+    #[test]
+    fn test_counts_queries_and_errors() {
+        let recorder = MetricsRecorder::default();
+
+        recorder.record(QueryOperation::Query, Duration::ZERO, None);
+        recorder.record(QueryOperation::QueryOne, Duration::ZERO, Some(&PostgresModelError::Timeout));
+        recorder.record(QueryOperation::QueryOne, Duration::ZERO, Some(&PostgresModelError::Timeout));
+
+        let queries = recorder.queries();
+        assert_eq!(queries["query"], 1);
+        assert_eq!(queries["query_one"], 2);
+        assert_eq!(queries["execute"], 0);
+
+        let errors = recorder.errors();
+        assert_eq!(errors["Timeout"], 2);
+        assert_eq!(errors.len(), 1);
+    }
+}
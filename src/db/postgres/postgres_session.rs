@@ -0,0 +1,221 @@
+use std::sync::Arc;
+
+use deadpool_postgres::{Hook, HookError, PoolBuilder};
+use futures_util::future::BoxFuture;
+use tokio::time::Duration;
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::Database;
+use crate::tiny_safe_string::TinySafeString;
+
+/*
+   Session setup for pooled connections.
+
+        let init = SessionInit {
+            search_path: vec!["app".into(), "public".into()],
+            time_zone: Some("UTC".into()),
+            statement_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+
+        let database = Database::new(url, 8, None)?
+            .with_session_init(init)?
+            .with_session_reset(SessionReset::DiscardAll)?
+            .with_post_create_hook(|client| Box::pin(async move {
+                client.execute("SELECT set_config('app.instance', $1, false)", &[&instance_id]).await?;
+                Ok(())
+            }))?;
+
+   The init SQL and the post-create hooks run once, when the pool opens a connection.
+   A session reset runs every time a connection is handed out again, followed by the
+   init SQL so the settings above survive it. Post-create hooks are not run again.
+*/
+
+pub type SessionHook =
+    Arc<dyn for<'a> Fn(&'a tokio_postgres::Client) -> BoxFuture<'a, Result<(), PostgresModelError>> + Send + Sync>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionInit {
+    pub search_path: Vec<TinySafeString>,
+    // e.g. UTC or Europe/Berlin
+    pub time_zone: Option<String>,
+    pub statement_timeout: Option<Duration>,
+    pub role: Option<TinySafeString>,
+    // Run after the settings above, in order
+    pub statements: Vec<String>,
+}
+
+impl SessionInit {
+    pub fn to_sql(&self) -> String {
+        let mut statements = Vec::new();
+
+        if !self.search_path.is_empty() {
+            let schemas: Vec<String> = self
+                .search_path
+                .iter()
+                .map(|schema| format!("\"{}\"", schema))
+                .collect();
+            statements.push(format!("SET search_path TO {}", schemas.join(", ")));
+        }
+
+        if let Some(time_zone) = &self.time_zone {
+            statements.push(format!("SET TIME ZONE '{}'", time_zone.replace('\'', "''")));
+        }
+
+        if let Some(statement_timeout) = self.statement_timeout {
+            statements.push(format!("SET statement_timeout = {}", statement_timeout.as_millis()));
+        }
+
+        if let Some(role) = &self.role {
+            statements.push(format!("SET ROLE \"{}\"", role));
+        }
+
+        statements.extend(self.statements.iter().map(|s| s.trim().trim_end_matches(';').to_string()));
+
+        statements
+            .iter()
+            .map(|statement| format!("{};", statement))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SessionReset {
+    // Hand connections out as they were returned
+    #[default]
+    None,
+    // Also drops prepared statements, so the connection's statement cache is cleared with it
+    DiscardAll,
+    // Must not deallocate prepared statements, the statement cache relies on them
+    Custom(String),
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct SessionSettings {
+    pub(crate) init: Option<SessionInit>,
+    pub(crate) reset: SessionReset,
+    pub(crate) post_create_hooks: Vec<SessionHook>,
+}
+
+impl SessionSettings {
+    fn init_sql(&self) -> Option<String> {
+        self.init.as_ref().map(SessionInit::to_sql).filter(|sql| !sql.is_empty())
+    }
+
+    // Runs when a connection is handed out again, before the init SQL is reapplied.
+    // Sent on its own because DISCARD ALL cannot run in a multi-statement batch.
+    fn reset_sql(&self) -> Option<String> {
+        match &self.reset {
+            SessionReset::None => None,
+            SessionReset::DiscardAll => Some("DISCARD ALL".to_string()),
+            SessionReset::Custom(sql) => Some(sql.clone()),
+        }
+    }
+
+    pub(crate) fn apply_hooks(&self, mut builder: PoolBuilder) -> PoolBuilder {
+        if let Some(init_sql) = self.init_sql() {
+            builder = builder.post_create(Hook::async_fn(move |client, _| {
+                let init_sql = init_sql.clone();
+                Box::pin(async move { client.batch_execute(&init_sql).await.map_err(HookError::Backend) })
+            }));
+        }
+
+        for hook in &self.post_create_hooks {
+            let hook = Arc::clone(hook);
+            builder = builder.post_create(Hook::async_fn(move |client, _| {
+                let hook = Arc::clone(&hook);
+                Box::pin(async move {
+                    hook(client)
+                        .await
+                        .map_err(|e| HookError::message(e.to_string()))
+                })
+            }));
+        }
+
+        if let Some(reset_sql) = self.reset_sql() {
+            let init_sql = self.init_sql();
+            let discards_statements = self.reset == SessionReset::DiscardAll;
+
+            // A failing reset makes the pool drop the connection and open a new one
+            builder = builder.post_recycle(Hook::async_fn(move |client, _| {
+                let reset_sql = reset_sql.clone();
+                let init_sql = init_sql.clone();
+                Box::pin(async move {
+                    client.batch_execute(&reset_sql).await.map_err(HookError::Backend)?;
+                    if discards_statements {
+                        client.statement_cache.clear();
+                    }
+                    if let Some(init_sql) = init_sql {
+                        client.batch_execute(&init_sql).await.map_err(HookError::Backend)?;
+                    }
+                    Ok(())
+                })
+            }));
+        }
+
+        builder
+    }
+}
+
+impl Database {
+    pub fn with_session_init(mut self, init: SessionInit) -> Result<Self, PostgresModelError> {
+        self.session.init = Some(init);
+        self.rebuild_pool()?;
+        Ok(self)
+    }
+
+    pub fn with_session_reset(mut self, reset: SessionReset) -> Result<Self, PostgresModelError> {
+        self.session.reset = reset;
+        self.rebuild_pool()?;
+        Ok(self)
+    }
+
+    pub fn with_post_create_hook<F>(mut self, hook: F) -> Result<Self, PostgresModelError>
+    where
+        F: for<'a> Fn(&'a tokio_postgres::Client) -> BoxFuture<'a, Result<(), PostgresModelError>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.session.post_create_hooks.push(Arc::new(hook));
+        self.rebuild_pool()?;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_init_sql() {
+        let init = SessionInit {
+            search_path: vec!["tenant_a".into(), "public".into()],
+            time_zone: Some("UTC".into()),
+            statement_timeout: Some(Duration::from_secs(30)),
+            role: Some("app_rw".into()),
+            statements: vec!["SET lock_timeout = '5s';".into()],
+        };
+
+        assert_eq!(
+            init.to_sql(),
+            "SET search_path TO \"tenant_a\", \"public\"; SET TIME ZONE 'UTC'; \
+             SET statement_timeout = 30000; SET ROLE \"app_rw\"; SET lock_timeout = '5s';"
+        );
+        assert_eq!(SessionInit::default().to_sql(), "");
+    }
+
+    #[test]
+    fn test_reset_sql() {
+        let mut session = SessionSettings::default();
+        assert_eq!(session.reset_sql(), None);
+        assert_eq!(session.init_sql(), None);
+
+        session.reset = SessionReset::DiscardAll;
+        assert_eq!(session.reset_sql().as_deref(), Some("DISCARD ALL"));
+
+        session.reset = SessionReset::Custom("RESET ALL; UNLISTEN *".into());
+        assert_eq!(session.reset_sql().as_deref(), Some("RESET ALL; UNLISTEN *"));
+    }
+}
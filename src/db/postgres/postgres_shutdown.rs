@@ -0,0 +1,188 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use log::{info, warn};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tokio::time::{sleep, Duration};
+
+use crate::db::postgres::postgres_db::Database;
+
+/*
+   Graceful shutdown.
+
+        // on SIGTERM
+        let report = database.shutdown(Duration::from_secs(10)).await;
+        if report.force_closed > 0 {
+            warn!("{} postgres connections were still busy at shutdown", report.force_closed);
+        }
+
+   `shutdown` stops handing out clients (`query`, `get_client`, ... fail with
+   `PoolError(Closed)`), closes the idle connections and waits for clients that are in use
+   to be returned, closing them as they come back. Connections opened by `Database::connect`
+   are waited on too; they finish once their `Client` is dropped. `listen` connections are
+   closed right away and their `NotificationStream` ends.
+   Whatever is still open when the timeout runs out is closed by force.
+*/
+
+// How often `shutdown` checks whether everything was returned
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// The tasks driving open connections, so they can be closed by force
+#[derive(Clone)]
+pub(crate) struct ConnectionTasks {
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+    // Set by `Database::close`, for tasks that can wind down on their own
+    closing: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ConnectionTasks {
+    fn default() -> Self {
+        Self {
+            tasks: Arc::default(),
+            closing: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl ConnectionTasks {
+    pub(crate) fn register(&self, task: AbortHandle) {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    pub(crate) fn open(&self) -> usize {
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.len()
+    }
+
+    pub(crate) fn close(&self) {
+        self.closing.send_replace(true);
+    }
+
+    // Resolves once `close` was called, right away if it already was
+    pub(crate) async fn closed(&self) {
+        let mut closing = self.closing.subscribe();
+        let _ = closing.wait_for(|closing| *closing).await;
+    }
+
+    pub(crate) fn abort_all(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    // Connections that were idle or returned before the timeout, then closed
+    pub closed: usize,
+    // Connections still in use when the timeout ran out
+    pub force_closed: usize,
+    pub elapsed: Duration,
+}
+
+impl Database {
+    // Stops handing out clients and closes idle connections, without waiting for the ones in use.
+    // Those are closed when they are returned.
+    pub fn close(&self) {
+        self.pool.close();
+        self.standalone_connections.close();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.pool.is_closed()
+    }
+
+    fn open_connections(&self) -> usize {
+        self.pool.status().size + self.standalone_connections.open()
+    }
+
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let started = Instant::now();
+
+        let open_at_start = self.open_connections();
+
+        self.close();
+        let deadline = started + timeout;
+
+        while self.open_connections() > 0 && Instant::now() < deadline {
+            sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        // Counted before aborting, the tasks of connections closed a moment ago may still be winding down
+        let force_closed = self.open_connections();
+        self.pool_connections.abort_all();
+        self.standalone_connections.abort_all();
+
+        let report = ShutdownReport {
+            closed: open_at_start.saturating_sub(force_closed),
+            force_closed,
+            elapsed: started.elapsed(),
+        };
+
+        if force_closed > 0 {
+            warn!(
+                "Database shutdown timed out after {:?}, force closed {} connections",
+                timeout, force_closed
+            );
+        } else {
+            info!("Database shutdown closed {} connections", report.closed);
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::postgres::test_support::test_database;
+
+    #[tokio::test]
+    async fn test_abort_all_closes_open_tasks() {
+        let tasks = ConnectionTasks::default();
+
+        let finished = tokio::spawn(async {});
+        tasks.register(finished.abort_handle());
+        let _ = finished.await;
+
+        let pending = tokio::spawn(std::future::pending::<()>());
+        tasks.register(pending.abort_handle());
+
+        assert_eq!(tasks.open(), 1);
+        tasks.abort_all();
+        assert!(pending.await.unwrap_err().is_cancelled());
+        assert_eq!(tasks.open(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_closed_resolves_after_close() {
+        let tasks = ConnectionTasks::default();
+
+        let waiting = tokio::spawn({
+            let tasks = tasks.clone();
+            async move { tasks.closed().await }
+        });
+
+        tasks.close();
+        waiting.await.unwrap();
+
+        // Also for tasks that start waiting later
+        tasks.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_pool() {
+        let database = test_database();
+
+        let report = database.shutdown(Duration::from_secs(1)).await;
+
+        assert_eq!(report.closed, 0);
+        assert_eq!(report.force_closed, 0);
+        assert!(database.is_closed());
+        assert!(database.get_client().await.is_err());
+    }
+}
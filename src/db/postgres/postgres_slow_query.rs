@@ -0,0 +1,378 @@
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+use tokio::time::{timeout_at, Duration, Instant};
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::{Database, QueryContext};
+use crate::db::postgres::postgres_metrics::QueryOperation;
+
+/*
+   Slow query log.
+
+   Any query that takes longer than `Database::slow_query_threshold` is logged with its
+   SQL and elapsed time, and handed to every registered `SlowQuerySink`.
+
+   The log is off by default. With `explain_slow_queries` turned on (meant for development
+   and CI, it costs an extra round trip per slow query) the statement is also run through
+   `EXPLAIN (ANALYZE false, FORMAT JSON)` and the plan is attached to the event. Queries in a
+   transaction or for a tenant are explained on their own connection, so the plan sees the
+   same search_path and uncommitted changes, other queries on a new pooled connection once
+   theirs is back in the pool. Streams are not explained, they still hold their connection.
+
+        let sink = MemorySlowQuerySink::default();
+
+        let database = Database::new(url, 8, None)?
+            .with_slow_query_threshold(Some(Duration::from_millis(50)))
+            .with_explain_slow_queries(true)
+            .with_slow_query_sink(Arc::new(sink.clone()));
+
+        run_the_test_suite(&database).await;
+
+        for event in sink.events() {
+            let scans = event.seq_scans_over(10_000);
+            assert!(scans.is_empty(), "{} seq scans {:?}", event.query, scans);
+        }
+*/
+
+// The longest EXPLAIN waits for a pooled connection, it holds up the caller meanwhile
+const EXPLAIN_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Statements that EXPLAIN accepts
+const EXPLAINABLE_KEYWORDS: [&str; 7] = ["SELECT", "INSERT", "UPDATE", "DELETE", "VALUES", "WITH", "MERGE"];
+
+#[derive(Debug, Clone)]
+pub struct SlowQuery {
+    pub operation: QueryOperation,
+    pub query: String,
+    // Known when the query was built with SqlBuilder
+    pub table: Option<String>,
+    pub elapsed: Duration,
+    pub threshold: Duration,
+    // Set when the query itself failed, e.g. on a statement timeout
+    pub error: Option<String>,
+    // The `EXPLAIN (FORMAT JSON)` output, when `explain_slow_queries` is on and the EXPLAIN succeeded
+    pub plan: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeqScan {
+    pub relation: String,
+    // The planner's row estimate for the scan
+    pub plan_rows: f64,
+}
+
+impl SlowQuery {
+    // Every `Seq Scan` node in the plan, empty when no plan was captured
+    pub fn seq_scans(&self) -> Vec<SeqScan> {
+        let mut scans = Vec::new();
+
+        if let Some(plan) = &self.plan {
+            collect_seq_scans(plan, &mut scans);
+        }
+
+        scans
+    }
+
+    // Seq scans the planner expects to read more than `min_rows` rows from
+    pub fn seq_scans_over(&self, min_rows: u64) -> Vec<SeqScan> {
+        self.seq_scans()
+            .into_iter()
+            .filter(|scan| scan.plan_rows > min_rows as f64)
+            .collect()
+    }
+}
+
+fn collect_seq_scans(value: &serde_json::Value, scans: &mut Vec<SeqScan>) {
+    match value {
+        serde_json::Value::Array(items) => {
+            for item in items {
+                collect_seq_scans(item, scans);
+            }
+        }
+        serde_json::Value::Object(node) => {
+            if node.get("Node Type").and_then(|t| t.as_str()) == Some("Seq Scan") {
+                scans.push(SeqScan {
+                    relation: node
+                        .get("Relation Name")
+                        .and_then(|r| r.as_str())
+                        .unwrap_or_default()
+                        .to_string(),
+                    plan_rows: node.get("Plan Rows").and_then(|r| r.as_f64()).unwrap_or(0.0),
+                });
+            }
+
+            for child in node.values() {
+                collect_seq_scans(child, scans);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub trait SlowQuerySink: Send + Sync {
+    fn record(&self, event: &SlowQuery);
+}
+
+// Keeps every event in memory, for asserting on in tests
+#[derive(Clone, Default)]
+pub struct MemorySlowQuerySink {
+    events: Arc<Mutex<Vec<SlowQuery>>>,
+}
+
+impl MemorySlowQuerySink {
+    pub fn events(&self) -> Vec<SlowQuery> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+}
+
+impl SlowQuerySink for MemorySlowQuerySink {
+    fn record(&self, event: &SlowQuery) {
+        self.events.lock().unwrap().push(event.clone());
+    }
+}
+
+fn is_explainable(query: &str) -> bool {
+    query
+        .split_whitespace()
+        .next()
+        .map(|keyword| {
+            EXPLAINABLE_KEYWORDS
+                .iter()
+                .any(|explainable| keyword.eq_ignore_ascii_case(explainable))
+        })
+        .unwrap_or(false)
+}
+
+impl Database {
+    pub fn with_slow_query_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    pub fn with_explain_slow_queries(mut self, explain_slow_queries: bool) -> Self {
+        self.explain_slow_queries = explain_slow_queries;
+        self
+    }
+
+    pub fn with_slow_query_sink(mut self, sink: Arc<dyn SlowQuerySink>) -> Self {
+        self.slow_query_sinks.push(sink);
+        self
+    }
+
+    pub(crate) fn is_slow(&self, elapsed: Duration) -> bool {
+        self.slow_query_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+    }
+
+    pub(crate) async fn report_slow_query(
+        &self,
+        context: &QueryContext<'_>,
+        client: Option<&deadpool_postgres::Client>,
+        elapsed: Duration,
+        error: Option<&PostgresModelError>,
+    ) {
+        let Some(threshold) = self.slow_query_threshold else {
+            return;
+        };
+
+        warn!(
+            "Slow query ({:?} > {:?}): {}",
+            elapsed,
+            threshold,
+            context.query.trim()
+        );
+
+        let plan = if self.explain_slow_queries {
+            self.explain(context, client).await
+        } else {
+            None
+        };
+
+        let event = SlowQuery {
+            operation: context.operation,
+            query: context.query.to_string(),
+            table: context.table.map(str::to_string),
+            elapsed,
+            threshold,
+            error: error.map(|e| e.to_string()),
+            plan,
+        };
+
+        for sink in &self.slow_query_sinks {
+            sink.record(&event);
+        }
+    }
+
+    // Not prepared through the statement cache, so it never evicts the application's statements
+    async fn explain(
+        &self,
+        context: &QueryContext<'_>,
+        client: Option<&deadpool_postgres::Client>,
+    ) -> Option<serde_json::Value> {
+        // A stream still holds its connection, checking out another could wait forever
+        if context.operation == QueryOperation::QueryStream || !is_explainable(context.query) {
+            return None;
+        }
+
+        // Never past the deadline of a *_with_timeout call
+        let checkout_deadline = match context.deadline {
+            Some(deadline) => deadline.min(Instant::now() + EXPLAIN_CHECKOUT_TIMEOUT),
+            None => Instant::now() + EXPLAIN_CHECKOUT_TIMEOUT,
+        };
+
+        let explain_query = format!("EXPLAIN (ANALYZE false, FORMAT JSON) {}", context.query);
+
+        let result = async {
+            let row = match client {
+                Some(client) => client.query_one(&explain_query, context.params).await?,
+                None => {
+                    let client = timeout_at(checkout_deadline, self.pool.get())
+                        .await
+                        .map_err(|_| PostgresModelError::Timeout)??;
+                    client.query_one(&explain_query, context.params).await?
+                }
+            };
+            Ok::<_, PostgresModelError>(row.try_get::<_, serde_json::Value>(0)?)
+        }
+        .await;
+
+        match result {
+            Ok(plan) => Some(plan),
+            Err(e) => {
+                debug!("Could not explain slow query: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::postgres::test_support::test_database;
+
+    fn slow_query(plan: serde_json::Value) -> SlowQuery {
+        SlowQuery {
+            operation: QueryOperation::Query,
+            query: "SELECT * FROM invoices JOIN payments ON payments.invoice_id = invoices.id".into(),
+            table: None,
+            elapsed: Duration::from_secs(2),
+            threshold: Duration::from_secs(1),
+            error: None,
+            plan: Some(plan),
+        }
+    }
+
+    #[test]
+    fn test_finds_nested_seq_scans() {
+        let event = slow_query(serde_json::json!([{
+            "Plan": {
+                "Node Type": "Hash Join",
+                "Plan Rows": 5000,
+                "Plans": [
+                    { "Node Type": "Seq Scan", "Relation Name": "invoices", "Plan Rows": 120000 },
+                    {
+                        "Node Type": "Hash",
+                        "Plans": [
+                            { "Node Type": "Seq Scan", "Relation Name": "payments", "Plan Rows": 40 }
+                        ]
+                    }
+                ]
+            }
+        }]));
+
+        let relations: Vec<String> = event.seq_scans().into_iter().map(|s| s.relation).collect();
+        assert_eq!(relations, vec!["invoices", "payments"]);
+
+        let big = event.seq_scans_over(10_000);
+        assert_eq!(big.len(), 1);
+        assert_eq!(big[0].relation, "invoices");
+    }
+
+    #[test]
+    fn test_is_explainable() {
+        assert!(is_explainable("  select 1"));
+        assert!(is_explainable("WITH x AS (SELECT 1) SELECT * FROM x"));
+        assert!(is_explainable("UPDATE invoices SET paid = true"));
+        assert!(!is_explainable("CREATE TABLE invoices (id int)"));
+        assert!(!is_explainable("EXPLAIN SELECT 1"));
+        assert!(!is_explainable(""));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_explains_on_the_transaction_connection() {
+        let sink = MemorySlowQuerySink::default();
+
+        // A single connection, held by the transaction, so a second checkout would never return
+        let database = test_database()
+            .with_slow_query_threshold(Some(Duration::ZERO))
+            .with_explain_slow_queries(true)
+            .with_slow_query_sink(Arc::new(sink.clone()));
+
+        let transaction = database.transaction().await.unwrap();
+        transaction
+            .execute("CREATE TABLE slow_query_invoices (id int)", &[])
+            .await
+            .unwrap();
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            transaction.query("SELECT * FROM slow_query_invoices", &[]),
+        )
+        .await
+        .expect("explain waited for a second connection")
+        .unwrap();
+
+        transaction.rollback().await.unwrap();
+
+        // The table only exists inside the transaction
+        let event = sink.events().pop().unwrap();
+        assert_eq!(event.seq_scans()[0].relation, "slow_query_invoices");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_slow_stream_is_not_explained() {
+        use futures_util::StreamExt;
+
+        let sink = MemorySlowQuerySink::default();
+        let database = test_database()
+            .with_slow_query_threshold(Some(Duration::ZERO))
+            .with_explain_slow_queries(true)
+            .with_slow_query_sink(Arc::new(sink.clone()));
+
+        // The stream holds the only connection until it is dropped
+        let stream = tokio::time::timeout(
+            Duration::from_secs(5),
+            database.query_stream("SELECT generate_series(1, 3)", &[]),
+        )
+        .await
+        .expect("explain waited for the stream's connection")
+        .unwrap();
+        assert_eq!(stream.collect::<Vec<_>>().await.len(), 3);
+
+        let event = sink.events().pop().unwrap();
+        assert_eq!(event.operation, QueryOperation::QueryStream);
+        assert!(event.plan.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_explain_checkout_stops_at_the_deadline() {
+        let database = test_database().with_explain_slow_queries(true);
+        let _held = database.get_client().await.unwrap();
+
+        let context = QueryContext::new(QueryOperation::Query, "SELECT 1", &[])
+            .with_deadline(Instant::now() + Duration::from_millis(100));
+        let started = Instant::now();
+
+        assert!(database.explain(&context, None).await.is_none());
+        assert!(started.elapsed() < EXPLAIN_CHECKOUT_TIMEOUT);
+    }
+}
@@ -0,0 +1,254 @@
+use std::error::Error;
+
+use log::info;
+use tokio_postgres::SimpleQueryMessage;
+use tokio_postgres_migration::Migration;
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::{Database, MigrationAsStr, QueryContext};
+use crate::db::postgres::postgres_metrics::QueryOperation;
+use crate::tiny_safe_string::TinySafeString;
+
+/*
+   Schema-per-tenant routing.
+
+        let tenant = database.for_tenant("tenant_42")?;
+
+        tenant.create_schema().await?;
+        tenant.migrate().await?;
+
+        // runs with search_path = "tenant_42", public
+        let rows = tenant.query("SELECT * FROM invoices", &[]).await?;
+
+   Each call sets the search_path on the pooled connection it borrows and puts the previous
+   search_path back before the connection is returned. If that cannot be done (an error, or the
+   future being dropped half way) the connection is closed instead, so another tenant can never
+   end up on it. Tenant queries bypass the statement cache because the same SQL resolves to
+   different tables per tenant.
+*/
+
+pub struct TenantDatabase<'a> {
+    database: &'a Database,
+    schema: TinySafeString,
+}
+
+impl Database {
+    // Schema names must be non-empty and alphanumeric/underscore
+    pub fn for_tenant(&self, schema: &str) -> Result<TenantDatabase<'_>, PostgresModelError> {
+        // TinySafeString accepts "", which would render as the invalid identifier ""
+        if schema.is_empty() {
+            return Err(PostgresModelError::InvalidIdentifier("empty schema name".into()));
+        }
+
+        let schema = TinySafeString::new(schema).map_err(PostgresModelError::InvalidIdentifier)?;
+
+        Ok(TenantDatabase {
+            database: self,
+            schema,
+        })
+    }
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn search_path_sql(schema: &TinySafeString) -> String {
+    format!(
+        "SELECT current_setting('search_path'); SET search_path TO \"{}\", public",
+        schema
+    )
+}
+
+// A pooled client with the tenant's search_path set
+struct TenantClient {
+    // Taken by `release`
+    client: Option<deadpool_postgres::Client>,
+    previous_search_path: String,
+}
+
+impl TenantClient {
+    fn client(&self) -> &deadpool_postgres::Client {
+        self.client.as_ref().expect("tenant client already released")
+    }
+
+    async fn release(mut self) -> Result<(), PostgresModelError> {
+        let client = self.client.take().expect("tenant client already released");
+
+        let restore = format!(
+            "SELECT set_config('search_path', {}, false)",
+            quote_literal(&self.previous_search_path)
+        );
+
+        match client.batch_execute(&restore).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                drop(deadpool_postgres::Client::take(client));
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl Drop for TenantClient {
+    fn drop(&mut self) {
+        // Not released, so the search_path may still point at the tenant
+        if let Some(client) = self.client.take() {
+            drop(deadpool_postgres::Client::take(client));
+        }
+    }
+}
+
+impl TenantDatabase<'_> {
+    pub fn schema(&self) -> &str {
+        self.schema.as_str()
+    }
+
+    async fn tenant_client(&self) -> Result<TenantClient, PostgresModelError> {
+        let client = self.database.get_client().await?;
+
+        let messages = client.simple_query(&search_path_sql(&self.schema)).await?;
+        let previous_search_path = messages
+            .iter()
+            .find_map(|message| match message {
+                SimpleQueryMessage::Row(row) => row.get(0).map(str::to_string),
+                _ => None,
+            })
+            .ok_or(PostgresModelError::UnexpectedRowsCount)?;
+
+        Ok(TenantClient {
+            client: Some(client),
+            previous_search_path,
+        })
+    }
+
+    pub async fn query(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
+        let context = QueryContext::new(QueryOperation::Query, query, params);
+        let tenant_client = self.tenant_client().await?;
+
+        let result = self
+            .database
+            .instrumented_on(tenant_client.client(), &context, async {
+                Ok(tenant_client.client().query(query, params).await?)
+            })
+            .await;
+
+        let released = tenant_client.release().await;
+        let rows = result?;
+        released?;
+
+        Ok(rows)
+    }
+
+    pub async fn query_one(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<tokio_postgres::Row, PostgresModelError> {
+        let context = QueryContext::new(QueryOperation::QueryOne, query, params);
+        let tenant_client = self.tenant_client().await?;
+
+        let result = self
+            .database
+            .instrumented_on(tenant_client.client(), &context, async {
+                Ok(tenant_client.client().query_one(query, params).await?)
+            })
+            .await;
+
+        let released = tenant_client.release().await;
+        let row = result?;
+        released?;
+
+        Ok(row)
+    }
+
+    //use for insert, update, etc
+    pub async fn execute(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<u64, PostgresModelError> {
+        let context = QueryContext::new(QueryOperation::Execute, query, params);
+        let tenant_client = self.tenant_client().await?;
+
+        let result = self
+            .database
+            .instrumented_on(tenant_client.client(), &context, async {
+                Ok(tenant_client.client().execute(query, params).await?)
+            })
+            .await;
+
+        let released = tenant_client.release().await;
+        let rows_affected = result?;
+        released?;
+
+        Ok(rows_affected)
+    }
+
+    pub async fn create_schema(&self) -> Result<(), PostgresModelError> {
+        let client = self.database.get_client().await?;
+
+        client
+            .batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS \"{}\"", self.schema))
+            .await?;
+
+        Ok(())
+    }
+
+    // Runs the database's up migrations inside the tenant schema.
+    // Applied migrations are tracked per tenant, in "<schema>".migrations.
+    pub async fn migrate(&self) -> Result<(), Box<dyn Error>> {
+        let client = &mut self.database.connect().await?;
+
+        client
+            .batch_execute(&format!("SET search_path TO \"{}\", public", self.schema))
+            .await?;
+
+        let mut migrations = Database::read_migration_files(self.database.migrations_dir_path.clone());
+        let migration = Migration::new(format!("\"{}\".migrations", self.schema));
+
+        for up_migration in migrations.up.drain(..) {
+            info!("migrating tenant {} {}", self.schema, up_migration.0);
+
+            // execute non existing migrations
+            migration.up(client, &[up_migration.to_str()]).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::postgres::test_support::test_database;
+
+    #[test]
+    fn test_tenant_schema_is_validated() {
+        let database = test_database();
+
+        assert_eq!(database.for_tenant("tenant_42").unwrap().schema(), "tenant_42");
+        assert!(database.for_tenant("tenant-42").is_err());
+        assert!(database.for_tenant("public; DROP SCHEMA public").is_err());
+        assert!(matches!(
+            database.for_tenant(""),
+            Err(PostgresModelError::InvalidIdentifier(_))
+        ));
+    }
+
+    #[test]
+    fn test_search_path_sql() {
+        let schema = TinySafeString::new("tenant_42").unwrap();
+
+        assert_eq!(
+            search_path_sql(&schema),
+            "SELECT current_setting('search_path'); SET search_path TO \"tenant_42\", public"
+        );
+        assert_eq!(quote_literal("\"$user\", public"), "'\"$user\", public'");
+        assert_eq!(quote_literal("it's"), "'it''s'");
+    }
+}
@@ -0,0 +1,218 @@
+use log::warn;
+use tokio::time::{timeout_at, Duration, Instant};
+use tokio_postgres::{CancelToken, NoTls};
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::{Database, QueryContext};
+use crate::db::postgres::postgres_metrics::QueryOperation;
+
+/*
+   Per-call timeouts.
+
+        let rows = database
+            .query_with_timeout("SELECT * FROM report_rows($1)", &[&report_id], Duration::from_secs(30))
+            .await?;   // Err(PostgresModelError::Timeout) after 30s
+
+   The timeout covers the wait for a pooled connection as well as the query itself.
+   When the timeout runs out, or the future is dropped before the query finished, a cancel
+   request is sent to postgres so the query stops running on the server. The connection
+   is closed rather than returned to the pool, so a late cancel can never hit the next
+   query that would have run on it.
+*/
+
+// A pooled client whose running query is cancelled unless `finish` is called
+struct CancellableClient {
+    // Taken by `finish` / `cancel`
+    client: Option<deadpool_postgres::Client>,
+    cancel_token: CancelToken,
+}
+
+impl CancellableClient {
+    fn new(client: deadpool_postgres::Client) -> Self {
+        Self {
+            cancel_token: client.cancel_token(),
+            client: Some(client),
+        }
+    }
+
+    fn client(&self) -> &deadpool_postgres::Client {
+        self.client.as_ref().expect("client already finished")
+    }
+
+    // The query completed, hand the connection back to the pool
+    fn finish(mut self) {
+        self.client.take();
+    }
+
+    async fn cancel(mut self) {
+        if let Err(e) = self.cancel_token.cancel_query(NoTls).await {
+            warn!("Could not cancel timed out query: {}", e);
+        }
+
+        if let Some(client) = self.client.take() {
+            drop(deadpool_postgres::Client::take(client));
+        }
+    }
+}
+
+impl Drop for CancellableClient {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+
+        // Dropped mid query, Drop can't await so the cancel request is sent from a task
+        let cancel_token = self.cancel_token.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = cancel_token.cancel_query(NoTls).await {
+                    warn!("Could not cancel dropped query: {}", e);
+                }
+            });
+        }
+
+        drop(deadpool_postgres::Client::take(client));
+    }
+}
+
+impl Database {
+    // The wait for a connection counts against the deadline
+    async fn cancellable_client(&self, deadline: Instant) -> Result<CancellableClient, PostgresModelError> {
+        match timeout_at(deadline, self.pool.get()).await {
+            Ok(client) => Ok(CancellableClient::new(client?)),
+            Err(_) => Err(PostgresModelError::Timeout),
+        }
+    }
+
+    pub async fn query_with_timeout(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+        duration: Duration,
+    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
+        let deadline = Instant::now() + duration;
+        let context = QueryContext::new(QueryOperation::Query, query, params).with_deadline(deadline);
+
+        self.instrumented(&context, async {
+            let client = self.cancellable_client(deadline).await?;
+
+            match timeout_at(deadline, self.query_on(client.client(), query, params)).await {
+                Ok(result) => {
+                    client.finish();
+                    result
+                }
+                Err(_) => {
+                    client.cancel().await;
+                    Err(PostgresModelError::Timeout)
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn query_one_with_timeout(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+        duration: Duration,
+    ) -> Result<tokio_postgres::Row, PostgresModelError> {
+        let deadline = Instant::now() + duration;
+        let context = QueryContext::new(QueryOperation::QueryOne, query, params).with_deadline(deadline);
+
+        self.instrumented(&context, async {
+            let client = self.cancellable_client(deadline).await?;
+
+            match timeout_at(deadline, self.query_one_on(client.client(), query, params)).await {
+                Ok(result) => {
+                    client.finish();
+                    result
+                }
+                Err(_) => {
+                    client.cancel().await;
+                    Err(PostgresModelError::Timeout)
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn execute_with_timeout(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+        duration: Duration,
+    ) -> Result<u64, PostgresModelError> {
+        let deadline = Instant::now() + duration;
+        let context = QueryContext::new(QueryOperation::Execute, query, params).with_deadline(deadline);
+
+        self.instrumented(&context, async {
+            let client = self.cancellable_client(deadline).await?;
+
+            match timeout_at(deadline, self.execute_on(client.client(), query, params)).await {
+                Ok(result) => {
+                    client.finish();
+                    result
+                }
+                Err(_) => {
+                    client.cancel().await;
+                    Err(PostgresModelError::Timeout)
+                }
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::postgres::test_support::test_database;
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_timed_out_query_is_cancelled_on_the_server() {
+        let database = test_database();
+        let started = Instant::now();
+
+        let result = database
+            .query_with_timeout("SELECT pg_sleep(30) AS timeout_test", &[], Duration::from_millis(200))
+            .await;
+        assert!(matches!(result, Err(PostgresModelError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The cancel request is handled asynchronously by the server
+        let mut still_running = 1;
+        for _ in 0..50 {
+            let row = database
+                .query_one(
+                    "SELECT count(*) FROM pg_stat_activity \
+                     WHERE query LIKE '%AS timeout_test' AND state = 'active' AND pid <> pg_backend_pid()",
+                    &[],
+                )
+                .await
+                .unwrap();
+            still_running = row.get::<_, i64>(0);
+            if still_running == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(still_running, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_pool_wait_counts_against_the_timeout() {
+        let database = test_database();
+        let held = database.get_client().await.unwrap();
+        let started = Instant::now();
+
+        let result = database
+            .query_with_timeout("SELECT 1", &[], Duration::from_millis(200))
+            .await;
+        assert!(matches!(result, Err(PostgresModelError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        drop(held);
+    }
+}
@@ -0,0 +1,136 @@
+use tokio::time::Duration;
+use tokio_postgres::config::Host;
+use tracing::field::Empty;
+use tracing::Span;
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::{Database, QueryContext, RowCount};
+
+/*
+   Spans for the `tracing` cargo feature.
+
+   Field names follow the OpenTelemetry database semantic conventions so that
+   tracing-opentelemetry can export them as-is. Bound parameter values are only
+   recorded (as `db.query.parameters`) when `Database::trace_query_parameters` is set.
+*/
+
+// First keyword of the statement, e.g. SELECT or INSERT
+fn operation_name(query: &str) -> Option<String> {
+    query
+        .split_whitespace()
+        .next()
+        .map(|keyword| keyword.trim_end_matches(';').to_uppercase())
+}
+
+// `{db.operation.name} {db.collection.name}` as recommended for span names
+fn span_name(operation: Option<&str>, table: Option<&str>) -> String {
+    match (operation, table) {
+        (Some(operation), Some(table)) => format!("{} {}", operation, table),
+        (Some(operation), None) => operation.to_string(),
+        (None, Some(table)) => table.to_string(),
+        (None, None) => "postgresql".to_string(),
+    }
+}
+
+fn server_address(database: &Database) -> Option<&str> {
+    match database.pg_config.get_hosts().first() {
+        Some(Host::Tcp(host)) => Some(host.as_str()),
+        _ => None,
+    }
+}
+
+pub(crate) fn query_span(database: &Database, context: &QueryContext<'_>) -> Span {
+    let operation = operation_name(context.query);
+
+    let span = tracing::info_span!(
+        target: "degen_sql",
+        "db.query",
+        otel.name = %span_name(operation.as_deref(), context.table),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        db.system.name = "postgresql",
+        db.namespace = database.pg_config.get_dbname(),
+        db.operation.name = operation.as_deref(),
+        db.collection.name = context.table,
+        db.query.text = context.query,
+        db.query.parameters = Empty,
+        db.response.returned_rows = Empty,
+        db.response.status_code = Empty,
+        server.address = server_address(database),
+        server.port = database.pg_config.get_ports().first().copied(),
+        "error.type" = Empty,
+        duration_ms = Empty,
+    );
+
+    if database.trace_query_parameters {
+        span.record("db.query.parameters", format!("{:?}", context.params));
+    }
+
+    span
+}
+
+pub(crate) fn transaction_span(database: &Database) -> Span {
+    tracing::info_span!(
+        target: "degen_sql",
+        "db.transaction",
+        otel.name = "TRANSACTION",
+        otel.kind = "client",
+        otel.status_code = Empty,
+        db.system.name = "postgresql",
+        db.namespace = database.pg_config.get_dbname(),
+        server.address = server_address(database),
+        server.port = database.pg_config.get_ports().first().copied(),
+        // commit, rollback or abandoned
+        db.transaction.outcome = Empty,
+        "error.type" = Empty,
+        duration_ms = Empty,
+    )
+}
+
+pub(crate) fn record_error(span: &Span, error: &PostgresModelError) {
+    span.record("otel.status_code", "ERROR");
+    span.record("error.type", error.variant_name());
+
+    if let PostgresModelError::Postgres(e) = error {
+        if let Some(code) = e.code() {
+            span.record("db.response.status_code", code.code());
+        }
+    }
+}
+
+pub(crate) fn record_outcome<T: RowCount>(
+    span: &Span,
+    elapsed: Duration,
+    result: &Result<T, PostgresModelError>,
+) {
+    span.record("duration_ms", elapsed.as_secs_f64() * 1000.0);
+
+    match result {
+        Ok(value) => {
+            if let Some(rows) = value.row_count() {
+                span.record("db.response.returned_rows", rows);
+            }
+        }
+        Err(e) => record_error(span, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_name() {
+        assert_eq!(operation_name("  select * from invoices"), Some("SELECT".to_string()));
+        assert_eq!(operation_name("\n INSERT INTO events (name) VALUES ($1)"), Some("INSERT".to_string()));
+        assert_eq!(operation_name("begin;"), Some("BEGIN".to_string()));
+        assert_eq!(operation_name("   "), None);
+    }
+
+    #[test]
+    fn test_span_name() {
+        assert_eq!(span_name(Some("SELECT"), Some("invoices")), "SELECT invoices");
+        assert_eq!(span_name(Some("DELETE"), None), "DELETE");
+        assert_eq!(span_name(None, None), "postgresql");
+    }
+}
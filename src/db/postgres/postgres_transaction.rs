@@ -0,0 +1,165 @@
+use std::future::Future;
+use std::time::Instant;
+
+use crate::db::postgres::models::model::PostgresModelError;
+use crate::db::postgres::postgres_db::{Database, QueryContext};
+use crate::db::postgres::postgres_metrics::QueryOperation;
+#[cfg(feature = "tracing")]
+use crate::db::postgres::postgres_tracing;
+
+/*
+   A transaction on one pooled connection.
+
+        let transaction = database.transaction().await?;
+
+        transaction.execute("UPDATE invoices SET paid = true WHERE id = $1", &[&invoice_id]).await?;
+        transaction.execute("INSERT INTO payments (invoice_id) VALUES ($1)", &[&invoice_id]).await?;
+
+        transaction.commit().await?;
+
+   Dropping a transaction without calling `commit` or `rollback` closes its connection
+   instead of returning it to the pool, so postgres rolls the transaction back.
+*/
+
+pub struct DatabaseTransaction<'a> {
+    database: &'a Database,
+    // Taken by commit / rollback
+    client: Option<deadpool_postgres::Client>,
+    started: Instant,
+
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Database {
+    pub async fn transaction(&self) -> Result<DatabaseTransaction<'_>, PostgresModelError> {
+        let client = self.pool.get().await?;
+
+        client.batch_execute("BEGIN").await?;
+
+        Ok(DatabaseTransaction {
+            database: self,
+            client: Some(client),
+            started: Instant::now(),
+            #[cfg(feature = "tracing")]
+            span: postgres_tracing::transaction_span(self),
+        })
+    }
+}
+
+impl DatabaseTransaction<'_> {
+    pub fn client(&self) -> &deadpool_postgres::Client {
+        // Only None once commit / rollback consumed the transaction
+        self.client.as_ref().expect("transaction already finished")
+    }
+
+    // Runs the future inside the transaction span so query spans become its children
+    async fn in_span<F: Future>(&self, run: F) -> F::Output {
+        #[cfg(feature = "tracing")]
+        return tracing::Instrument::instrument(run, self.span.clone()).await;
+
+        #[cfg(not(feature = "tracing"))]
+        return run.await;
+    }
+
+    pub async fn query(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<Vec<tokio_postgres::Row>, PostgresModelError> {
+        let context = QueryContext::new(QueryOperation::Query, query, params);
+
+        self.in_span(self.database.instrumented_on(
+            self.client(),
+            &context,
+            self.database.query_on(self.client(), query, params),
+        ))
+        .await
+    }
+
+    pub async fn query_one(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<tokio_postgres::Row, PostgresModelError> {
+        let context = QueryContext::new(QueryOperation::QueryOne, query, params);
+
+        self.in_span(self.database.instrumented_on(
+            self.client(),
+            &context,
+            self.database.query_one_on(self.client(), query, params),
+        ))
+        .await
+    }
+
+    pub async fn execute(
+        &self,
+        query: &str,
+        params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
+    ) -> Result<u64, PostgresModelError> {
+        let context = QueryContext::new(QueryOperation::Execute, query, params);
+
+        self.in_span(self.database.instrumented_on(
+            self.client(),
+            &context,
+            self.database.execute_on(self.client(), query, params),
+        ))
+        .await
+    }
+
+    pub async fn commit(mut self) -> Result<(), PostgresModelError> {
+        self.finish("COMMIT").await
+    }
+
+    pub async fn rollback(mut self) -> Result<(), PostgresModelError> {
+        self.finish("ROLLBACK").await
+    }
+
+    async fn finish(&mut self, statement: &str) -> Result<(), PostgresModelError> {
+        let client = self.client.take().expect("transaction already finished");
+
+        let result = self
+            .in_span(client.batch_execute(statement))
+            .await
+            .map_err(PostgresModelError::from);
+
+        #[cfg(feature = "tracing")]
+        {
+            self.span
+                .record("duration_ms", self.started.elapsed().as_secs_f64() * 1000.0);
+            self.span
+                .record("db.transaction.outcome", statement.to_lowercase());
+            if let Err(e) = &result {
+                postgres_tracing::record_error(&self.span, e);
+            }
+        }
+
+        if result.is_err() {
+            // The connection is in an unknown state, so don't hand it out again
+            drop(deadpool_postgres::Client::take(client));
+        }
+
+        result
+    }
+}
+
+impl Drop for DatabaseTransaction<'_> {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+
+        #[cfg(feature = "tracing")]
+        {
+            self.span
+                .record("duration_ms", self.started.elapsed().as_secs_f64() * 1000.0);
+            self.span.record("db.transaction.outcome", "abandoned");
+        }
+
+        #[cfg(not(feature = "tracing"))]
+        let _ = self.started;
+
+        // Closing the connection makes postgres roll back the open transaction
+        drop(deadpool_postgres::Client::take(client));
+    }
+}
@@ -8,7 +8,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut database = Database::new(conn_url,8, None) ? ;
 
-    let _migration = database.migrate().await?;
+    database.migrate().await?;
 
     Ok(())
 }
@@ -17,7 +17,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 let mut database = Database::new(conn_url,8, None) ? ;
 
 
-            let _migration = database.rollback_full().await?;
+            database.rollback_full().await?;
 
             println!("Rollback complete");
         }
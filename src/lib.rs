@@ -5,4 +5,6 @@ pub mod sql_builder;
 
 pub mod pagination;
 
-pub mod tiny_safe_string;
\ No newline at end of file
+pub mod tiny_safe_string;
+
+pub mod secret_string;
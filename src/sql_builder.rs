@@ -1,8 +1,10 @@
 
 use std::sync::Arc;
 use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
 
 use tokio_postgres::types::ToSql;
+use crate::db::postgres::models::model::PostgresModelError;
 use crate::pagination::PaginationData;
 use crate::tiny_safe_string::TinySafeString;
 
@@ -23,14 +25,14 @@ use crate::tiny_safe_string::TinySafeString;
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::SelectAll,
             table_name: "invoices".to_string(),
-            where_params,
+            where_conditions: where_params.into(),
             order: Some(("created_at".to_string(), OrderingDirection::DESC)),
             limit: None,
             pagination: pagination.cloned(),
         };
         
         // Build the SQL query and parameters
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build()?;
         
 
          let built_params = &params.iter().map(|x| &**x).collect::<Vec<_>>();
@@ -57,12 +59,152 @@ use crate::tiny_safe_string::TinySafeString;
 */
 
 
+/*
+   Conditions.
+
+        let builder = SqlBuilder::new(SqlStatementBase::SelectAll, "invoices")
+            .where_eq("chain_id", chain_id)
+            .where_or([
+                Condition::eq("status", "paid".to_string()),
+                Condition::and([
+                    Condition::eq("status", "refunded".to_string()),
+                    !Condition::null("refunded_at"),
+                ]),
+            ]);
+
+        // SELECT * FROM invoices WHERE chain_id = $1 AND (status = $2 OR (status = $3 AND NOT (refunded_at IS NULL)))
+
+   The where_* methods and `where_condition` AND onto `where_conditions`, in the order they are called.
+   A column can be filtered more than once, which is what ranges need:
+
+        let builder = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_range("block_number", from_block..to_block)
+            .where_between("created_at", since, until);
+
+        // SELECT * FROM events WHERE block_number >= $1 AND block_number < $2 AND created_at BETWEEN $3 AND $4
+
+
+   JSONB conditions.
+
+        let builder = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_json_contains("args", json!({ "from": from_address }))
+            .where_json_has_key("data", "amount")
+            .where_json_field("data", "amount", ComparisonType::GT, json!(1000))
+            .where_json_path_text("args", vec!["payer".into(), "chain".into()], ComparisonType::EQ, "base".to_string());
+
+        // SELECT * FROM events WHERE args @> $1 AND data ? $2 AND (data -> $3) > $4 AND (args #>> $5) = $6
+
+   JSON keys and paths are bound as parameters too, never written into the query.
+   The columns must be jsonb; the ? and @? operators don't exist for json.
+
+
+   Selecting columns.
+
+        let builder = SqlBuilder::new(SqlStatementBase::SelectAll, "payments")
+            .column("id")
+            .select(SelectExpr::column("payer_address").alias("payer"))
+            .where_eq("chain_id", chain_id);
+
+        // SELECT id, payer_address AS payer FROM payments WHERE chain_id = $1
+
+        let totals = SqlBuilder::new(SqlStatementBase::SelectAll, "payments")
+            .select(SelectExpr::count_distinct("payer_address").alias("payers"))
+            .select(SelectExpr::coalesce([SelectExpr::sum("amount"), SelectExpr::Integer(0)]).alias("total"))
+            .where_eq("chain_id", chain_id);
+
+        // SELECT COUNT(DISTINCT payer_address) AS payers, COALESCE(SUM(amount), 0) AS total FROM payments WHERE chain_id = $1
+
+   Adding columns replaces the `*` of SelectAll; on SelectCountAll they are added after COUNT(*).
+
+
+   Inserts.
+
+        let builder = SqlBuilder::new(SqlStatementBase::Insert, "events")
+            .value("chain_id", chain_id)
+            .value("contract_address", contract_address)
+            .value_default("created_at")
+            .returning("id");
+
+        let (query, params) = builder.build()?;
+        // INSERT INTO events (chain_id, contract_address, created_at) VALUES ($1, $2, DEFAULT) RETURNING id
+
+        let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
+        let id: i32 = database.query_one(&query, &built_params).await?.get("id");
+
+   Columns are written in name order. An insert without values becomes `DEFAULT VALUES`.
+   The WHERE, ORDER BY, LIMIT and pagination parts are not used by plain inserts.
+
+
+   Updates.
+
+        let builder = SqlBuilder::new(SqlStatementBase::Update, "accounts")
+            .value("status", "active".to_string())
+            .value_increment("login_count", 1_i32)
+            .value_now("updated_at")
+            .where_eq("id", account_id)
+            .returning("login_count");
+
+        // UPDATE accounts SET login_count = login_count + $1, status = $2, updated_at = NOW() WHERE id = $3 RETURNING login_count
+
+   The SET parameters are numbered first and the WHERE parameters continue after them.
+   An UPDATE or DELETE without WHERE conditions is refused by `build`, which returns
+   an error unless `allow_unfiltered()` was called.
 
+
+   Upserts.
+
+        let builder = SqlBuilder::new(SqlStatementBase::Insert, "premium_status")
+            .value("owner_address", owner_address)
+            .value("chain_id", chain_id)
+            .value("expires_at", expires_at)
+            .on_conflict_do_update(ConflictTarget::columns(["owner_address", "chain_id"]), ["expires_at"])
+            .on_conflict_set("renewals", SqlValue::Increment(Arc::new(1_i32)))
+            .where_lt("expires_at", expires_at)
+            .returning("id");
+
+        // INSERT INTO premium_status (chain_id, expires_at, owner_address) VALUES ($1, $2, $3)
+        //   ON CONFLICT (owner_address, chain_id) DO UPDATE SET expires_at = EXCLUDED.expires_at,
+        //   renewals = premium_status.renewals + $4 WHERE premium_status.expires_at < $5
+        //   RETURNING id, (xmax = 0) AS upsert_inserted
+
+        match database.upsert_sql_builder(&builder).await? {
+            UpsertOutcome::Inserted(row) => ..,
+            UpsertOutcome::Updated(row) => ..,
+            // DO NOTHING, or the WHERE didn't match
+            UpsertOutcome::Skipped => ..,
+        }
+
+   On an upsert the WHERE conditions decide which conflicting rows get updated.
+   Columns there, and in increments, refer to the existing row.
+
+
+   Batch inserts.
+
+        let mut batch = BatchInsert::new("events", ["chain_id", "block_number", "tx_hash"]);
+        for event in events {
+            batch.push_row(vec![
+                Arc::new(event.chain_id),
+                Arc::new(event.block_number),
+                Arc::new(event.tx_hash),
+            ]);
+        }
+
+        // INSERT INTO events (chain_id, block_number, tx_hash) VALUES ($1, $2, $3), ($4, $5, $6), ...
+        let inserted = database.execute_batch_insert(&batch).await?;
+
+   Rows are split over as many statements as needed to stay under `MAX_BIND_PARAMS`
+   bind parameters per statement. `execute_batch_insert` runs them all in one
+   transaction, so either every row is inserted or none are.
+*/
+
+
+#[derive(Default)]
 pub struct SqlBuilder {
 	pub statement_base: SqlStatementBase,
 	pub table_name : String, 
  
-	pub where_params: BTreeMap<TinySafeString, (ComparisonType, Arc<dyn ToSql + Sync>)>, 
+	// The where_* methods AND their conditions onto this
+	pub where_conditions: Condition, 
     
 	pub order: Option<(TinySafeString,OrderingDirection)> , 
  
@@ -72,6 +214,23 @@ pub struct SqlBuilder {
 	
 	// Optional pagination that overrides order, limit and offset when provided
 	pub pagination: Option<PaginationData>,
+
+	// Column values for inserts, or the SET clause of updates
+	pub values: BTreeMap<TinySafeString, SqlValue>,
+
+	pub returning: Vec<TinySafeString>,
+
+	// UPDATE and DELETE refuse to build without WHERE conditions unless this is set
+	pub allow_unfiltered: bool,
+
+	// Turns an insert into an upsert
+	pub on_conflict: Option<OnConflict>,
+
+	// Columns passed to `on_conflict_set` while there was no DO UPDATE to add them to, refused by `build`
+	misplaced_conflict_sets: Vec<TinySafeString>,
+
+	// Set when `column`, `select` or `distinct` was called on an INSERT, UPDATE or DELETE, refused by `build`
+	misplaced_select_list: bool,
 }
 
 impl SqlBuilder {
@@ -81,72 +240,314 @@ impl SqlBuilder {
                 SqlBuilder {
                     statement_base,
                     table_name: table_name.into(),
-                    where_params: BTreeMap::new(),
+                    where_conditions: Condition::default(),
                     order: None,
                     limit: None,
                     pagination: None,
+                    values: BTreeMap::new(),
+                    returning: Vec::new(),
+                    allow_unfiltered: false,
+                    on_conflict: None,
+                    misplaced_conflict_sets: Vec::new(),
+                    misplaced_select_list: false,
                 }
             }
             
             // Add a where condition with equality comparison
             pub fn where_eq(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static
          ) -> Self {
-                self.where_params.insert(key.into(), (ComparisonType::EQ, Arc::new(value) as Arc<dyn ToSql + Sync>));
+                self.push_condition(Condition::compare(key, ComparisonType::EQ, value));
                 self
             }
             
            // Add a where condition with less than comparison
             pub fn where_lt(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static
          ) -> Self {
-                self.where_params.insert(key.into(), (ComparisonType::LT, Arc::new(value) as Arc<dyn ToSql + Sync>));
+                self.push_condition(Condition::compare(key, ComparisonType::LT, value));
                 self
            }
            
            // Add a where condition with greater than comparison
            pub fn where_gt(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static
          ) -> Self {
-               self.where_params.insert(key.into(), (ComparisonType::GT, Arc::new(value) as Arc<dyn ToSql + Sync>));
+               self.push_condition(Condition::compare(key, ComparisonType::GT, value));
                self
            }
            
            // Add a where condition with less than or equal comparison
            pub fn where_lte(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
-               self.where_params.insert(key.into(), (ComparisonType::LTE, Arc::new(value) as Arc<dyn ToSql + Sync>));
+               self.push_condition(Condition::compare(key, ComparisonType::LTE, value));
                self
            }
            
            // Add a where condition with greater than or equal comparison
            pub fn where_gte(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
-               self.where_params.insert(key.into(), (ComparisonType::GTE, Arc::new(value) as Arc<dyn ToSql + Sync>));
+               self.push_condition(Condition::compare(key, ComparisonType::GTE, value));
                self
            }
            
            // Add a where condition with LIKE comparison
            pub fn where_like(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
-               self.where_params.insert(key.into(), (ComparisonType::LIKE, Arc::new(value) as Arc<dyn ToSql + Sync>));
+               self.push_condition(Condition::compare(key, ComparisonType::LIKE, value));
                self
            }
            
-           // Add a where condition with IN comparison
-           pub fn where_in(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static
-         ) -> Self {
-               self.where_params.insert(key.into(), (ComparisonType::IN, Arc::new(value) as Arc<dyn ToSql + Sync>));
+           // Add a where condition matching any of the values, column = ANY($1)
+           pub fn where_in<T>(mut self, key: impl Into<TinySafeString>, values: Vec<T>) -> Self
+           where
+               T: ToSql + Sync + 'static,
+           {
+               self.push_condition(Condition::is_in(key, values));
+               self
+           }
+
+           // Add a where condition matching none of the values, column <> ALL($1)
+           pub fn where_not_in<T>(mut self, key: impl Into<TinySafeString>, values: Vec<T>) -> Self
+           where
+               T: ToSql + Sync + 'static,
+           {
+               self.push_condition(Condition::not_in(key, values));
                self
            }
            
            // Add a where condition with IS NULL comparison
            pub fn where_null(mut self, key: impl Into<TinySafeString>) -> Self {
-               // The value doesn't matter for NULL comparison, just using a dummy value
-               self.where_params.insert(key.into(), (ComparisonType::NULL, Arc::new(0_i32) as Arc<dyn ToSql + Sync>));
+               self.push_condition(Condition::null(key));
                self
            }
            
+           // Add a where condition with IS NOT NULL comparison
+           pub fn where_not_null(mut self, key: impl Into<TinySafeString>) -> Self {
+               self.push_condition(Condition::not_null(key));
+               self
+           }
+
+           // Add a where condition with not equal (<>) comparison
+           pub fn where_ne(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::ne(key, value));
+               self
+           }
+
+           // Add a where condition with NOT LIKE comparison
+           pub fn where_not_like(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::not_like(key, value));
+               self
+           }
+
+           // Add a where condition with case insensitive ILIKE comparison
+           pub fn where_ilike(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::ilike(key, value));
+               self
+           }
+
+           // Add a where condition with NOT ILIKE comparison
+           pub fn where_not_ilike(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::not_ilike(key, value));
+               self
+           }
+
+           // Add a where condition matching a regular expression (~)
+           pub fn where_regex(mut self, key: impl Into<TinySafeString>, pattern: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::regex(key, pattern));
+               self
+           }
+
+           // Add a where condition matching a case insensitive regular expression (~*)
+           pub fn where_iregex(mut self, key: impl Into<TinySafeString>, pattern: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::iregex(key, pattern));
+               self
+           }
+
+           // Add a where condition with IS DISTINCT FROM comparison
+           pub fn where_distinct_from(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::distinct_from(key, value));
+               self
+           }
+
+           // Add a where condition with IS NOT DISTINCT FROM comparison
+           pub fn where_not_distinct_from(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.push_condition(Condition::not_distinct_from(key, value));
+               self
+           }
+
+           // Add a LIKE condition matching values that contain `value` literally
+           pub fn where_contains(mut self, key: impl Into<TinySafeString>, value: &str) -> Self {
+               self.push_condition(Condition::contains(key, value));
+               self
+           }
+
+           // Add a LIKE condition matching values that start with `value` literally
+           pub fn where_starts_with(mut self, key: impl Into<TinySafeString>, value: &str) -> Self {
+               self.push_condition(Condition::starts_with(key, value));
+               self
+           }
+
+           // Add a LIKE condition matching values that end with `value` literally
+           pub fn where_ends_with(mut self, key: impl Into<TinySafeString>, value: &str) -> Self {
+               self.push_condition(Condition::ends_with(key, value));
+               self
+           }
+
+           // Add a jsonb containment condition, column @> $1
+           pub fn where_json_contains(mut self, key: impl Into<TinySafeString>, value: serde_json::Value) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::Contains(value)));
+               self
+           }
+
+           // Add a condition that the jsonb column has the top level key, column ? $1
+           pub fn where_json_has_key(mut self, key: impl Into<TinySafeString>, json_key: impl Into<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::HasKey(json_key.into())));
+               self
+           }
+
+           // Add a condition that the jsonb column has any of the keys, column ?| $1
+           pub fn where_json_has_any_key(mut self, key: impl Into<TinySafeString>, json_keys: Vec<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::HasAnyKey(json_keys)));
+               self
+           }
+
+           // Add a condition that the jsonb column has all of the keys, column ?& $1
+           pub fn where_json_has_all_keys(mut self, key: impl Into<TinySafeString>, json_keys: Vec<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::HasAllKeys(json_keys)));
+               self
+           }
+
+           // Compare a field of the jsonb column as jsonb, (column -> $1) = $2
+           pub fn where_json_field(
+               mut self,
+               key: impl Into<TinySafeString>,
+               json_key: impl Into<String>,
+               comparison: ComparisonType,
+               value: serde_json::Value,
+           ) -> Self {
+               self.push_condition(Condition::json(
+                   key,
+                   JsonCondition::Field { key: json_key.into(), comparison, value },
+               ));
+               self
+           }
+
+           // Compare a field of the jsonb column as text, (column ->> $1) = $2
+           pub fn where_json_field_text(
+               mut self,
+               key: impl Into<TinySafeString>,
+               json_key: impl Into<String>,
+               comparison: ComparisonType,
+               value: impl ToSql + Sync + 'static,
+           ) -> Self {
+               self.push_condition(Condition::json(
+                   key,
+                   JsonCondition::FieldText { key: json_key.into(), comparison, value: Arc::new(value) },
+               ));
+               self
+           }
+
+           // Compare a nested field as jsonb, (column #> $1) = $2 with $1 e.g. ["payer", "address"]
+           pub fn where_json_path(
+               mut self,
+               key: impl Into<TinySafeString>,
+               path: Vec<String>,
+               comparison: ComparisonType,
+               value: serde_json::Value,
+           ) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::Path { path, comparison, value }));
+               self
+           }
+
+           // Compare a nested field as text, (column #>> $1) = $2
+           pub fn where_json_path_text(
+               mut self,
+               key: impl Into<TinySafeString>,
+               path: Vec<String>,
+               comparison: ComparisonType,
+               value: impl ToSql + Sync + 'static,
+           ) -> Self {
+               self.push_condition(Condition::json(
+                   key,
+                   JsonCondition::PathText { path, comparison, value: Arc::new(value) },
+               ));
+               self
+           }
+
+           // Add a condition that a field is missing or JSON null, (column ->> $1) IS NULL
+           pub fn where_json_field_null(mut self, key: impl Into<TinySafeString>, json_key: impl Into<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::FieldNull { key: json_key.into(), not_null: false }));
+               self
+           }
+
+           // Add a condition that a field is set to something other than JSON null, (column ->> $1) IS NOT NULL
+           pub fn where_json_field_not_null(mut self, key: impl Into<TinySafeString>, json_key: impl Into<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::FieldNull { key: json_key.into(), not_null: true }));
+               self
+           }
+
+           // Add a condition that the value at the path is missing or JSON null, (column #>> $1) IS NULL
+           pub fn where_json_path_null(mut self, key: impl Into<TinySafeString>, path: Vec<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::PathNull { path, not_null: false }));
+               self
+           }
+
+           // Add a condition that the value at the path is set to something other than JSON null, (column #>> $1) IS NOT NULL
+           pub fn where_json_path_not_null(mut self, key: impl Into<TinySafeString>, path: Vec<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::PathNull { path, not_null: true }));
+               self
+           }
+
+           // Add a jsonpath condition, column @? $1
+           pub fn where_jsonpath(mut self, key: impl Into<TinySafeString>, jsonpath: impl Into<String>) -> Self {
+               self.push_condition(Condition::json(key, JsonCondition::JsonPath(jsonpath.into())));
+               self
+           }
+
            // Add a generic where condition with custom comparison
            pub fn where_custom(mut self, key: impl Into<TinySafeString>, comparison_type: ComparisonType, value: impl ToSql + Sync + 'static) -> Self {
-               self.where_params.insert(key.into(), (comparison_type, Arc::new(value) as Arc<dyn ToSql + Sync>));
+               self.push_condition(Condition::compare(key, comparison_type, value));
                self
            }
            
+           // Add a condition tree, e.g. where_condition(Condition::or([Condition::eq("status", a), Condition::eq("status", b)]))
+           pub fn where_condition(mut self, condition: Condition) -> Self {
+               self.push_condition(condition);
+               self
+           }
+
+           // Add conditions of which at least one has to match
+           pub fn where_or(self, conditions: impl IntoIterator<Item = Condition>) -> Self {
+               self.where_condition(Condition::or(conditions))
+           }
+
+           // Add a BETWEEN condition, both ends inclusive
+           pub fn where_between(
+               mut self,
+               key: impl Into<TinySafeString>,
+               low: impl ToSql + Sync + 'static,
+               high: impl ToSql + Sync + 'static,
+           ) -> Self {
+               self.push_condition(Condition::between(key, low, high));
+               self
+           }
+
+           // Add conditions for a Rust range, e.g. where_range("block_number", from..to)
+           pub fn where_range<T>(mut self, key: impl Into<TinySafeString>, range: impl RangeBounds<T>) -> Self
+           where
+               T: ToSql + Sync + Clone + 'static,
+           {
+               self.push_condition(Condition::range(key, range));
+               self
+           }
+
+           fn push_condition(&mut self, condition: Condition) {
+               match (&mut self.where_conditions, condition) {
+                   // Kept flat, so they don't end up in parentheses
+                   (Condition::And(conditions), Condition::And(more)) => conditions.extend(more),
+                   (Condition::And(conditions), condition) => conditions.push(condition),
+                   (other, condition) => {
+                       let existing = std::mem::take(other);
+                       *other = Condition::And(vec![existing, condition]);
+                   }
+               }
+           }
+
            // Set the ORDER BY clause
            pub fn order_by(mut self, column: impl Into<TinySafeString>, direction: OrderingDirection) -> Self {
                self.order = Some((column.into(), direction));
@@ -165,61 +566,1039 @@ impl SqlBuilder {
                self
            }
 
+           // Set a column value, bound as a parameter
+           pub fn value(mut self, key: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+               self.values.insert(key.into(), SqlValue::Param(Arc::new(value)));
+               self
+           }
+
+           // Set a column to its DEFAULT
+           pub fn value_default(mut self, key: impl Into<TinySafeString>) -> Self {
+               self.values.insert(key.into(), SqlValue::Default);
+               self
+           }
+
+           // Set a column to NOW()
+           pub fn value_now(mut self, key: impl Into<TinySafeString>) -> Self {
+               self.values.insert(key.into(), SqlValue::Now);
+               self
+           }
+
+           // Add to a column's current value, e.g. counter = counter + $1. Updates only
+           pub fn value_increment(mut self, key: impl Into<TinySafeString>, amount: impl ToSql + Sync + 'static) -> Self {
+               self.values.insert(key.into(), SqlValue::Increment(Arc::new(amount)));
+               self
+           }
+
+           // Add a column to the RETURNING clause
+           pub fn returning(mut self, column: impl Into<TinySafeString>) -> Self {
+               self.returning.push(column.into());
+               self
+           }
+
+           // Select a column instead of *, turning a `SelectAll` or `SelectCountAll` into a `Select`
+           pub fn column(self, column: impl Into<TinySafeString>) -> Self {
+               self.select(SelectExpr::column(column))
+           }
+
+           // Select an expression, optionally aliased, e.g. select(SelectExpr::sum("amount").alias("total"))
+           pub fn select(mut self, column: impl Into<SelectColumn>) -> Self {
+               if let Some(list) = self.select_list_mut() {
+                   list.columns.push(column.into());
+               }
+               self
+           }
+
+           // SELECT DISTINCT
+           pub fn distinct(mut self) -> Self {
+               if let Some(list) = self.select_list_mut() {
+                   list.distinct = true;
+               }
+               self
+           }
+
+           // None for INSERT, UPDATE and DELETE, which are left as they are for `build` to refuse
+           fn select_list_mut(&mut self) -> Option<&mut SelectList> {
+               match self.statement_base {
+                   SqlStatementBase::SelectAll | SqlStatementBase::SelectCountAll => {
+                       let list = self.statement_base.select_list().unwrap_or_default();
+                       self.statement_base = SqlStatementBase::Select(list);
+                   }
+                   SqlStatementBase::Select(_) => {}
+                   SqlStatementBase::Delete | SqlStatementBase::Insert | SqlStatementBase::Update => {
+                       self.misplaced_select_list = true;
+                       return None;
+                   }
+               }
+
+               match &mut self.statement_base {
+                   SqlStatementBase::Select(list) => Some(list),
+                   _ => unreachable!(),
+               }
+           }
+
+           // Skip the insert when it conflicts with an existing row
+           pub fn on_conflict_do_nothing(mut self, target: ConflictTarget) -> Self {
+               self.on_conflict = Some(OnConflict {
+                   target,
+                   action: ConflictAction::DoNothing,
+               });
+               self
+           }
+
+           // Update the existing row instead, setting each of `columns` to its EXCLUDED value.
+           // Further assignments can be added with `on_conflict_set`.
+           pub fn on_conflict_do_update<I, C>(mut self, target: ConflictTarget, columns: I) -> Self
+           where
+               I: IntoIterator<Item = C>,
+               C: Into<TinySafeString>,
+           {
+               let set = columns.into_iter().map(|column| (column.into(), SqlValue::Excluded)).collect();
+               self.on_conflict = Some(OnConflict {
+                   target,
+                   action: ConflictAction::DoUpdate(set),
+               });
+               self
+           }
+
+           // Add an assignment to ON CONFLICT DO UPDATE. Must come after `on_conflict_do_update`,
+           // otherwise `build` returns an error
+           pub fn on_conflict_set(mut self, key: impl Into<TinySafeString>, value: SqlValue) -> Self {
+               match &mut self.on_conflict {
+                   Some(OnConflict { action: ConflictAction::DoUpdate(set), .. }) => {
+                       set.insert(key.into(), value);
+                   }
+                   _ => self.misplaced_conflict_sets.push(key.into()),
+               }
+               self
+           }
+
+           // Let an UPDATE or DELETE without WHERE conditions through, it touches every row
+           pub fn allow_unfiltered(mut self) -> Self {
+               self.allow_unfiltered = true;
+               self
+           }
+
+
+
+
+    // Returns an error for statements that can't be built, e.g. a DELETE without conditions,
+    // instead of rendering them.
+    pub fn build(&self) -> Result<(String, Vec<Arc<dyn ToSql + Sync>>), PostgresModelError> {
+        if !self.misplaced_conflict_sets.is_empty() {
+            let columns: Vec<&str> = self.misplaced_conflict_sets.iter().map(|column| column.as_str()).collect();
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "on_conflict_set({}) on {} without a preceding on_conflict_do_update",
+                columns.join(", "),
+                self.table_name
+            )));
+        }
+
+        if self.misplaced_select_list {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "selecting columns from {} needs a SELECT statement, use returning for INSERT, UPDATE and DELETE",
+                self.table_name
+            )));
+        }
+
+        self.where_conditions.check()?;
+
+        match self.statement_base {
+            SqlStatementBase::Insert => return self.build_insert(),
+            SqlStatementBase::Update => return self.build_update(),
+            SqlStatementBase::Delete => return self.build_delete(),
+            SqlStatementBase::SelectAll | SqlStatementBase::SelectCountAll | SqlStatementBase::Select(_) => {}
+        }
+
+        let mut query = format!("{} FROM {}", self.statement_base.build(), self.table_name);
+
+           let mut params: Vec<Arc<dyn ToSql + Sync>> = Vec::new();
+
+        query.push_str(&self.build_where(&mut params));
+
+        // Use pagination if provided, otherwise fall back to manual order and limit
+        if let Some(pagination) = &self.pagination {
+            // Append the pagination query part (includes ORDER BY, LIMIT, and OFFSET)
+            query.push_str(&format!(" {}", pagination.build_query_part()));
+        } else {
+            // ORDER BY clause
+            if let Some((column, direction)) = &self.order {
+                query.push_str(&format!(" ORDER BY {} {}", column, direction.build()));
+            }
+
+            // LIMIT clause
+            if let Some(limit) = self.limit {
+                query.push_str(&format!(" LIMIT {}", limit));
+            }
+        }
+
+        Ok(( query , params))
+    }
+
+    // Appends the WHERE parameters after the ones already in `params`
+    fn build_where(&self, params: &mut Vec<Arc<dyn ToSql + Sync>>) -> String {
+        self.build_conditions(None, params)
+    }
+
+    // Columns are prefixed with `qualifier.` when given
+    fn build_conditions(&self, qualifier: Option<&str>, params: &mut Vec<Arc<dyn ToSql + Sync>>) -> String {
+        if self.where_conditions.is_empty() {
+            return String::new();
+        }
+
+        format!(" WHERE {}", self.where_conditions.build(qualifier, params))
+    }
+
+    fn check_filtered(&self) -> Result<(), PostgresModelError> {
+        if self.where_conditions.is_empty() && !self.allow_unfiltered {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "{} on {} has no WHERE conditions, call allow_unfiltered() to touch every row",
+                self.statement_base.build(),
+                self.table_name
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn build_insert(&self) -> Result<(String, Vec<Arc<dyn ToSql + Sync>>), PostgresModelError> {
+        // Only an upsert's DO UPDATE has a WHERE, a plain INSERT would silently drop the conditions
+        if self.on_conflict.is_none() && !self.where_conditions.is_empty() {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "INSERT into {} can't have WHERE conditions",
+                self.table_name
+            )));
+        }
+
+        let mut query = format!("{} {}", self.statement_base.build(), self.table_name);
+        let mut params: Vec<Arc<dyn ToSql + Sync>> = Vec::new();
+
+        if self.values.is_empty() {
+            query.push_str(" DEFAULT VALUES");
+        } else {
+            let columns: Vec<&str> = self.values.keys().map(|key| key.as_str()).collect();
+            let mut placeholders = Vec::new();
+
+            for (key, value) in &self.values {
+                match value {
+                    SqlValue::Increment(_) | SqlValue::Excluded => {
+                        return Err(PostgresModelError::InvalidStatement(format!(
+                            "{} can only be set from its current or EXCLUDED value in an UPDATE",
+                            key
+                        )));
+                    }
+                    _ => placeholders.push(value.to_sql(key, None, &mut params)),
+                }
+            }
+
+            query.push_str(&format!(" ({}) VALUES ({})", columns.join(", "), placeholders.join(", ")));
+        }
+
+        if let Some(on_conflict) = &self.on_conflict {
+            query.push_str(&self.build_on_conflict(on_conflict, &mut params)?);
+
+            let mut returning: Vec<&str> = self.returning.iter().map(|column| column.as_str()).collect();
+            returning.push(UPSERT_INSERTED_RETURNING);
+            query.push_str(&format!(" RETURNING {}", returning.join(", ")));
+        } else {
+            query.push_str(&self.build_returning());
+        }
+
+        Ok((query, params))
+    }
+
+    fn build_on_conflict(
+        &self,
+        on_conflict: &OnConflict,
+        params: &mut Vec<Arc<dyn ToSql + Sync>>,
+    ) -> Result<String, PostgresModelError> {
+        let target = match &on_conflict.target {
+            ConflictTarget::Any => String::new(),
+            ConflictTarget::Columns(columns) => {
+                let columns: Vec<&str> = columns.iter().map(|column| column.as_str()).collect();
+                format!(" ({})", columns.join(", "))
+            }
+            ConflictTarget::Constraint(constraint) => format!(" ON CONSTRAINT {}", constraint),
+        };
+
+        let set = match &on_conflict.action {
+            // WHERE conditions only apply to the row DO UPDATE would change
+            ConflictAction::DoNothing if !self.where_conditions.is_empty() => {
+                return Err(PostgresModelError::InvalidStatement(format!(
+                    "ON CONFLICT DO NOTHING on {} can't have WHERE conditions",
+                    self.table_name
+                )));
+            }
+            ConflictAction::DoNothing => return Ok(format!(" ON CONFLICT{} DO NOTHING", target)),
+            ConflictAction::DoUpdate(set) => set,
+        };
+
+        if on_conflict.target == ConflictTarget::Any {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "ON CONFLICT DO UPDATE on {} needs conflict columns or a constraint",
+                self.table_name
+            )));
+        }
+        if set.is_empty() {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "ON CONFLICT DO UPDATE on {} has no values to set",
+                self.table_name
+            )));
+        }
+
+        // The existing row's columns are ambiguous with EXCLUDED unless qualified by the table
+        let table = self.table_name.as_str();
+        let assignments: Vec<String> = set
+            .iter()
+            .map(|(key, value)| format!("{} = {}", key, value.to_sql(key, Some(table), params)))
+            .collect();
+
+        let mut clause = format!(" ON CONFLICT{} DO UPDATE SET {}", target, assignments.join(", "));
+        clause.push_str(&self.build_conditions(Some(table), params));
+
+        Ok(clause)
+    }
+
+    fn build_update(&self) -> Result<(String, Vec<Arc<dyn ToSql + Sync>>), PostgresModelError> {
+        if self.values.is_empty() {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "UPDATE on {} has no values to set",
+                self.table_name
+            )));
+        }
+        self.check_filtered()?;
+
+        let mut params: Vec<Arc<dyn ToSql + Sync>> = Vec::new();
+
+        // SET parameters come first, the WHERE numbering continues after them
+        let assignments: Vec<String> = self
+            .values
+            .iter()
+            .map(|(key, value)| format!("{} = {}", key, value.to_sql(key, None, &mut params)))
+            .collect();
+
+        let mut query = format!(
+            "{} {} SET {}",
+            self.statement_base.build(),
+            self.table_name,
+            assignments.join(", ")
+        );
+        query.push_str(&self.build_where(&mut params));
+        query.push_str(&self.build_returning());
+
+        Ok((query, params))
+    }
+
+    fn build_delete(&self) -> Result<(String, Vec<Arc<dyn ToSql + Sync>>), PostgresModelError> {
+        self.check_filtered()?;
+
+        let mut params: Vec<Arc<dyn ToSql + Sync>> = Vec::new();
+
+        let mut query = format!("{} FROM {}", self.statement_base.build(), self.table_name);
+        query.push_str(&self.build_where(&mut params));
+        query.push_str(&self.build_returning());
+
+        Ok((query, params))
+    }
+
+    fn build_returning(&self) -> String {
+        if self.returning.is_empty() {
+            return String::new();
+        }
+
+        let columns: Vec<&str> = self.returning.iter().map(|column| column.as_str()).collect();
+        format!(" RETURNING {}", columns.join(", "))
+    }
+}
+
+
+// `lhs operator $n`, pushing the parameter when the comparison takes one
+fn compare_sql(
+    lhs: &str,
+    comparison: ComparisonType,
+    param: &Arc<dyn ToSql + Sync>,
+    params: &mut Vec<Arc<dyn ToSql + Sync>>,
+) -> String {
+    let operator = comparison.to_operator();
+
+    if !comparison.takes_param() {
+        return format!("{} {}", lhs, operator);
+    }
+
+    params.push(Arc::clone(param));
+    if comparison.takes_array() {
+        format!("{} {}(${})", lhs, operator, params.len())
+    } else {
+        format!("{} {} ${}", lhs, operator, params.len())
+    }
+}
+
+// Escapes the LIKE wildcards % and _ (and the escape character \) so the value matches literally
+pub fn escape_like_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn qualified(qualifier: Option<&str>, column: &TinySafeString) -> String {
+    match qualifier {
+        Some(qualifier) => format!("{}.{}", qualifier, column),
+        None => column.to_string(),
+    }
+}
+
+
+pub enum SqlValue {
+    Param(Arc<dyn ToSql + Sync>),
+    Default,
+    Now,
+    // column + $n
+    Increment(Arc<dyn ToSql + Sync>),
+    // EXCLUDED.column, the value proposed for insertion. ON CONFLICT DO UPDATE only
+    Excluded,
+}
+
+impl SqlValue {
+    // Pushes the bound value, if any, and returns what goes in its place in the query
+    fn to_sql(
+        &self,
+        column: &TinySafeString,
+        qualifier: Option<&str>,
+        params: &mut Vec<Arc<dyn ToSql + Sync>>,
+    ) -> String {
+        match self {
+            Self::Param(param) => {
+                params.push(Arc::clone(param));
+                format!("${}", params.len())
+            }
+            Self::Default => "DEFAULT".to_string(),
+            Self::Now => "NOW()".to_string(),
+            Self::Increment(amount) => {
+                params.push(Arc::clone(amount));
+                format!("{} + ${}", qualified(qualifier, column), params.len())
+            }
+            Self::Excluded => format!("EXCLUDED.{}", column),
+        }
+    }
+}
+
+
+// Appended to the RETURNING list of upserts, true when the row was inserted rather than updated
+pub const UPSERT_INSERTED_COLUMN: &str = "upsert_inserted";
+const UPSERT_INSERTED_RETURNING: &str = "(xmax = 0) AS upsert_inserted";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictTarget {
+    // Any unique violation, DO NOTHING only
+    Any,
+    Columns(Vec<TinySafeString>),
+    Constraint(TinySafeString),
+}
+
+impl ConflictTarget {
+    pub fn columns<I, C>(columns: I) -> Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<TinySafeString>,
+    {
+        Self::Columns(columns.into_iter().map(Into::into).collect())
+    }
+}
+
+pub enum ConflictAction {
+    DoNothing,
+    DoUpdate(BTreeMap<TinySafeString, SqlValue>),
+}
+
+pub struct OnConflict {
+    pub target: ConflictTarget,
+    pub action: ConflictAction,
+}
+
+#[derive(Debug)]
+pub enum UpsertOutcome {
+    Inserted(tokio_postgres::Row),
+    Updated(tokio_postgres::Row),
+    // Conflicted with DO NOTHING, or the DO UPDATE WHERE didn't match
+    Skipped,
+}
+
+impl UpsertOutcome {
+    pub fn from_row(row: Option<tokio_postgres::Row>) -> Result<Self, PostgresModelError> {
+        let Some(row) = row else {
+            return Ok(Self::Skipped);
+        };
+
+        let inserted: bool = row
+            .try_get(UPSERT_INSERTED_COLUMN)
+            .map_err(|e| PostgresModelError::RowParseError(Some(e.to_string())))?;
+
+        Ok(if inserted { Self::Inserted(row) } else { Self::Updated(row) })
+    }
+
+    pub fn row(&self) -> Option<&tokio_postgres::Row> {
+        match self {
+            Self::Inserted(row) | Self::Updated(row) => Some(row),
+            Self::Skipped => None,
+        }
+    }
+}
+
+
+
+
+// Most bind parameters one statement can take. The protocol allows 65,535 but
+// tokio-postgres sends the count as a signed 16 bit integer, so it stops at 32,767.
+pub const MAX_BIND_PARAMS: usize = i16::MAX as usize;
+
+// A query and its parameters
+pub type BuiltQuery = (String, Vec<Arc<dyn ToSql + Sync>>);
+
+pub struct BatchInsert {
+    pub table_name: String,
+    pub columns: Vec<TinySafeString>,
+    // One value per column, in column order
+    pub rows: Vec<Vec<Arc<dyn ToSql + Sync>>>,
+    pub max_params_per_statement: usize,
+}
+
+impl BatchInsert {
+    pub fn new<I, C>(table_name: impl Into<String>, columns: I) -> Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<TinySafeString>,
+    {
+        BatchInsert {
+            table_name: table_name.into(),
+            columns: columns.into_iter().map(Into::into).collect(),
+            rows: Vec::new(),
+            max_params_per_statement: MAX_BIND_PARAMS,
+        }
+    }
+
+    pub fn row(mut self, values: Vec<Arc<dyn ToSql + Sync>>) -> Self {
+        self.rows.push(values);
+        self
+    }
+
+    pub fn push_row(&mut self, values: Vec<Arc<dyn ToSql + Sync>>) {
+        self.rows.push(values);
+    }
+
+    fn params_per_statement(&self) -> usize {
+        self.max_params_per_statement.min(MAX_BIND_PARAMS)
+    }
+
+    // Rows that fit in one statement, at least one once `build` checked the column count
+    fn rows_per_statement(&self) -> usize {
+        self.params_per_statement() / self.columns.len()
+    }
+
+    // One INSERT per chunk of rows, empty when there are no rows
+    pub fn build(&self) -> Result<Vec<BuiltQuery>, PostgresModelError> {
+        if self.columns.is_empty() {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "batch insert into {} has no columns",
+                self.table_name
+            )));
+        }
+
+        if let Some(index) = self.rows.iter().position(|row| row.len() != self.columns.len()) {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "batch insert into {}: row {} has {} values for {} columns",
+                self.table_name,
+                index,
+                self.rows[index].len(),
+                self.columns.len()
+            )));
+        }
+
+        // Not even a single row would fit in a statement
+        if self.columns.len() > self.params_per_statement() {
+            return Err(PostgresModelError::InvalidStatement(format!(
+                "batch insert into {} has {} columns, more than the {} parameters allowed per statement",
+                self.table_name,
+                self.columns.len(),
+                self.params_per_statement()
+            )));
+        }
+
+        let columns: Vec<&str> = self.columns.iter().map(|column| column.as_str()).collect();
+        let prefix = format!("INSERT INTO {} ({}) VALUES ", self.table_name, columns.join(", "));
+
+        let statements = self
+            .rows
+            .chunks(self.rows_per_statement())
+            .map(|chunk| {
+                let mut params: Vec<Arc<dyn ToSql + Sync>> = Vec::new();
+                let tuples: Vec<String> = chunk
+                    .iter()
+                    .map(|row| {
+                        let placeholders: Vec<String> = row
+                            .iter()
+                            .map(|value| {
+                                params.push(Arc::clone(value));
+                                format!("${}", params.len())
+                            })
+                            .collect();
+                        format!("({})", placeholders.join(", "))
+                    })
+                    .collect();
+
+                (format!("{}{}", prefix, tuples.join(", ")), params)
+            })
+            .collect();
+
+        Ok(statements)
+    }
+}
+
+
+
+
+// WHERE conditions. Parameters are numbered in the order they appear in the query
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Compare {
+        column: TinySafeString,
+        comparison: ComparisonType,
+        // Unused for NULL and NOT_NULL
+        param: Arc<dyn ToSql + Sync>,
+    },
+    // column BETWEEN low AND high, both ends inclusive
+    Between {
+        column: TinySafeString,
+        low: Arc<dyn ToSql + Sync>,
+        high: Arc<dyn ToSql + Sync>,
+    },
+    // Always true when empty
+    And(Vec<Condition>),
+    // Always false when empty
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+    Json {
+        column: TinySafeString,
+        condition: JsonCondition,
+    },
+}
+
+impl Default for Condition {
+    fn default() -> Self {
+        Self::And(Vec::new())
+    }
+}
+
+impl Condition {
+    pub fn compare(
+        column: impl Into<TinySafeString>,
+        comparison: ComparisonType,
+        value: impl ToSql + Sync + 'static,
+    ) -> Self {
+        Self::Compare {
+            column: column.into(),
+            comparison,
+            param: Arc::new(value),
+        }
+    }
+
+    pub fn eq(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::EQ, value)
+    }
+
+    pub fn lt(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::LT, value)
+    }
+
+    pub fn gt(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::GT, value)
+    }
+
+    pub fn lte(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::LTE, value)
+    }
+
+    pub fn gte(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::GTE, value)
+    }
+
+    pub fn like(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::LIKE, value)
+    }
+
+    // An empty list matches no rows
+    pub fn is_in<T>(column: impl Into<TinySafeString>, values: Vec<T>) -> Self
+    where
+        T: ToSql + Sync + 'static,
+    {
+        if values.is_empty() {
+            return Self::Or(Vec::new());
+        }
+
+        Self::compare(column, ComparisonType::IN, values)
+    }
+
+    // An empty list excludes nothing, so it isn't a filter, also inside an OR (an UPDATE or
+    // DELETE still needs allow_unfiltered). Rows where the column is NULL never match.
+    pub fn not_in<T>(column: impl Into<TinySafeString>, values: Vec<T>) -> Self
+    where
+        T: ToSql + Sync + 'static,
+    {
+        if values.is_empty() {
+            return Self::And(Vec::new());
+        }
+
+        Self::compare(column, ComparisonType::NOT_IN, values)
+    }
+
+    pub fn null(column: impl Into<TinySafeString>) -> Self {
+        // The value doesn't matter for NULL comparison, just using a dummy value
+        Self::compare(column, ComparisonType::NULL, 0_i32)
+    }
+
+    pub fn not_null(column: impl Into<TinySafeString>) -> Self {
+        Self::compare(column, ComparisonType::NOT_NULL, 0_i32)
+    }
+
+    pub fn ne(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::NE, value)
+    }
+
+    pub fn not_like(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::NOT_LIKE, value)
+    }
+
+    pub fn ilike(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::ILIKE, value)
+    }
+
+    pub fn not_ilike(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::NOT_ILIKE, value)
+    }
+
+    pub fn regex(column: impl Into<TinySafeString>, pattern: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::REGEX, pattern)
+    }
+
+    pub fn iregex(column: impl Into<TinySafeString>, pattern: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::IREGEX, pattern)
+    }
+
+    pub fn distinct_from(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::DISTINCT_FROM, value)
+    }
+
+    pub fn not_distinct_from(column: impl Into<TinySafeString>, value: impl ToSql + Sync + 'static) -> Self {
+        Self::compare(column, ComparisonType::NOT_DISTINCT_FROM, value)
+    }
+
+    // The value is matched literally, % and _ in it are escaped
+    pub fn contains(column: impl Into<TinySafeString>, value: &str) -> Self {
+        Self::like(column, format!("%{}%", escape_like_pattern(value)))
+    }
+
+    pub fn starts_with(column: impl Into<TinySafeString>, value: &str) -> Self {
+        Self::like(column, format!("{}%", escape_like_pattern(value)))
+    }
+
+    pub fn ends_with(column: impl Into<TinySafeString>, value: &str) -> Self {
+        Self::like(column, format!("%{}", escape_like_pattern(value)))
+    }
+
+    pub fn between(
+        column: impl Into<TinySafeString>,
+        low: impl ToSql + Sync + 'static,
+        high: impl ToSql + Sync + 'static,
+    ) -> Self {
+        Self::Between {
+            column: column.into(),
+            low: Arc::new(low),
+            high: Arc::new(high),
+        }
+    }
+
+    // a..b becomes column >= a AND column < b, a..=b column >= a AND column <= b.
+    // Open ends are left out, so `..` doesn't filter at all.
+    pub fn range<T>(column: impl Into<TinySafeString>, range: impl RangeBounds<T>) -> Self
+    where
+        T: ToSql + Sync + Clone + 'static,
+    {
+        let column = column.into();
+        let mut conditions = Vec::new();
+
+        match range.start_bound() {
+            Bound::Included(start) => conditions.push(Self::gte(column.clone(), start.clone())),
+            Bound::Excluded(start) => conditions.push(Self::gt(column.clone(), start.clone())),
+            Bound::Unbounded => {}
+        }
+
+        match range.end_bound() {
+            Bound::Included(end) => conditions.push(Self::lte(column, end.clone())),
+            Bound::Excluded(end) => conditions.push(Self::lt(column, end.clone())),
+            Bound::Unbounded => {}
+        }
+
+        Self::And(conditions)
+    }
+
+    pub fn json(column: impl Into<TinySafeString>, condition: JsonCondition) -> Self {
+        Self::Json {
+            column: column.into(),
+            condition,
+        }
+    }
+
+    pub fn and(conditions: impl IntoIterator<Item = Condition>) -> Self {
+        Self::And(conditions.into_iter().collect())
+    }
+
+    pub fn or(conditions: impl IntoIterator<Item = Condition>) -> Self {
+        Self::Or(conditions.into_iter().collect())
+    }
+
+    // True when there is nothing to filter on, i.e. the condition matches every row.
+    // An OR with such a branch matches every row too, however the other branches look.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::And(conditions) => conditions.iter().all(Condition::is_empty),
+            Self::Or(conditions) => conditions.iter().any(Condition::is_empty),
+            Self::Not(condition) => condition.is_always_false(),
+            _ => false,
+        }
+    }
+
+    // True when the condition can't match any row, e.g. an empty `is_in`
+    fn is_always_false(&self) -> bool {
+        match self {
+            Self::And(conditions) => conditions.iter().any(Condition::is_always_false),
+            Self::Or(conditions) => conditions.iter().all(Condition::is_always_false),
+            Self::Not(condition) => condition.is_empty(),
+            _ => false,
+        }
+    }
+
+    // Appends the parameters after the ones already in `params`
+    fn build(&self, qualifier: Option<&str>, params: &mut Vec<Arc<dyn ToSql + Sync>>) -> String {
+        match self {
+            Self::Compare {
+                column,
+                comparison,
+                param,
+            } => compare_sql(&qualified(qualifier, column), *comparison, param, params),
+            Self::Json { column, condition } => condition.build(&qualified(qualifier, column), params),
+            Self::Between { column, low, high } => {
+                params.push(Arc::clone(low));
+                params.push(Arc::clone(high));
+                format!(
+                    "{} BETWEEN ${} AND ${}",
+                    qualified(qualifier, column),
+                    params.len() - 1,
+                    params.len()
+                )
+            }
+            Self::And(conditions) => {
+                let parts: Vec<String> = conditions
+                    .iter()
+                    .filter(|condition| !condition.is_empty())
+                    .map(|condition| condition.build_nested(qualifier, params))
+                    .collect();
+
+                if parts.is_empty() {
+                    "TRUE".to_string()
+                } else {
+                    parts.join(" AND ")
+                }
+            }
+            Self::Or(conditions) => {
+                let parts: Vec<String> = conditions
+                    .iter()
+                    .map(|condition| condition.build_nested(qualifier, params))
+                    .collect();
+
+                if parts.is_empty() {
+                    "FALSE".to_string()
+                } else {
+                    parts.join(" OR ")
+                }
+            }
+            Self::Not(condition) => format!("NOT ({})", condition.build(qualifier, params)),
+        }
+    }
+
+    // Refuses conditions that would only fail once postgres runs them
+    fn check(&self) -> Result<(), PostgresModelError> {
+        match self {
+            Self::Compare { .. } | Self::Between { .. } => Ok(()),
+            Self::Json { column, condition } => condition.check(column),
+            Self::And(conditions) | Self::Or(conditions) => conditions.iter().try_for_each(Condition::check),
+            Self::Not(condition) => condition.check(),
+        }
+    }
+
+    // Groups of more than one condition get parentheses inside other groups
+    fn build_nested(&self, qualifier: Option<&str>, params: &mut Vec<Arc<dyn ToSql + Sync>>) -> String {
+        let grouped = match self {
+            Self::And(conditions) => conditions.iter().filter(|condition| !condition.is_empty()).count() > 1,
+            Self::Or(conditions) => conditions.len() > 1,
+            _ => false,
+        };
+
+        if grouped {
+            format!("({})", self.build(qualifier, params))
+        } else {
+            self.build(qualifier, params)
+        }
+    }
+}
+
+// NOT (condition)
+impl std::ops::Not for Condition {
+    type Output = Condition;
+
+    fn not(self) -> Condition {
+        Condition::Not(Box::new(self))
+    }
+}
 
+// ANDs the conditions of a flat column map, in column order
+impl From<BTreeMap<TinySafeString, (ComparisonType, Arc<dyn ToSql + Sync>)>> for Condition {
+    fn from(where_params: BTreeMap<TinySafeString, (ComparisonType, Arc<dyn ToSql + Sync>)>) -> Self {
+        Self::And(
+            where_params
+                .into_iter()
+                .map(|(column, (comparison, param))| Self::Compare {
+                    column,
+                    comparison,
+                    param,
+                })
+                .collect(),
+        )
+    }
+}
 
 
-    pub fn build(&self) -> (String , Vec<Arc<dyn ToSql + Sync>>  ) {
-        let mut query = format!("{} FROM {}", self.statement_base.build(), self.table_name);
-        let mut conditions = Vec::new();
-       
+// Conditions on a jsonb column. Keys, paths and values are all bound as parameters
+#[derive(Debug, Clone)]
+pub enum JsonCondition {
+    // column @> $1
+    Contains(serde_json::Value),
+    // column ? $1
+    HasKey(String),
+    // column ?| $1
+    HasAnyKey(Vec<String>),
+    // column ?& $1
+    HasAllKeys(Vec<String>),
+    // (column -> $1) = $2, compared as jsonb, so 2 and 10 compare as numbers
+    Field {
+        key: String,
+        comparison: ComparisonType,
+        value: serde_json::Value,
+    },
+    // (column ->> $1) = $2, compared as text
+    FieldText {
+        key: String,
+        comparison: ComparisonType,
+        value: Arc<dyn ToSql + Sync>,
+    },
+    // (column #> $1) = $2, with $1 the path as an array of keys
+    Path {
+        path: Vec<String>,
+        comparison: ComparisonType,
+        value: serde_json::Value,
+    },
+    // (column #>> $1) = $2
+    PathText {
+        path: Vec<String>,
+        comparison: ComparisonType,
+        value: Arc<dyn ToSql + Sync>,
+    },
+    // (column ->> $1) IS NULL, or IS NOT NULL. A missing field counts as NULL, like JSON null.
+    FieldNull {
+        key: String,
+        not_null: bool,
+    },
+    // (column #>> $1) IS NULL, or IS NOT NULL
+    PathNull {
+        path: Vec<String>,
+        not_null: bool,
+    },
+    // column @? $1, a jsonpath like '$.items[*] ? (@.price > 10)'
+    JsonPath(String),
+}
 
-           let mut params: Vec<Arc<dyn ToSql + Sync>> = Vec::new();
-        
-        // WHERE conditions
-        for (key, (comparison_type, param)) in &self.where_params {
-            params.push(Arc::clone(param)); // Clone Arc reference
-            
-            let operator = comparison_type.to_operator();
-            if *comparison_type == ComparisonType::IN {
-                conditions.push(format!("{} {} (${})", key, operator, params.len()));
-            } else if *comparison_type == ComparisonType::NULL {
-                conditions.push(format!("{} {}", key, operator));
-                // Pop the last parameter as NULL doesn't need a parameter
-                params.pop();
-            } else {
-                conditions.push(format!("{} {} ${}", key, operator, params.len()));
+impl JsonCondition {
+    fn check(&self, column: &str) -> Result<(), PostgresModelError> {
+        match self {
+            // The value is bound as one jsonb value, not an array of them
+            Self::Field { comparison, .. } | Self::Path { comparison, .. } if comparison.takes_array() => {
+                Err(PostgresModelError::InvalidStatement(format!(
+                    "{:?} on a jsonb field of {} isn't supported, compare the field as text instead",
+                    comparison, column
+                )))
             }
+            _ => Ok(()),
         }
+    }
 
-        if !conditions.is_empty() {
-            query.push_str(" WHERE ");
-            query.push_str(&conditions.join(" AND "));
-        }
+    fn build(&self, column: &str, params: &mut Vec<Arc<dyn ToSql + Sync>>) -> String {
+        let mut bind = |param: Arc<dyn ToSql + Sync>| {
+            params.push(param);
+            params.len()
+        };
 
-        // Use pagination if provided, otherwise fall back to manual order and limit
-        if let Some(pagination) = &self.pagination {
-            // Append the pagination query part (includes ORDER BY, LIMIT, and OFFSET)
-            query.push_str(&format!(" {}", pagination.build_query_part()));
-        } else {
-            // ORDER BY clause
-            if let Some((column, direction)) = &self.order {
-                query.push_str(&format!(" ORDER BY {} {}", column, direction.build()));
+        match self {
+            Self::Contains(value) => format!("{} @> ${}", column, bind(Arc::new(value.clone()))),
+            Self::HasKey(key) => format!("{} ? ${}", column, bind(Arc::new(key.clone()))),
+            Self::HasAnyKey(keys) => format!("{} ?| ${}", column, bind(Arc::new(keys.clone()))),
+            Self::HasAllKeys(keys) => format!("{} ?& ${}", column, bind(Arc::new(keys.clone()))),
+            // Bound as text, there is no jsonpath ToSql
+            Self::JsonPath(path) => format!("{} @? ${}::text::jsonpath", column, bind(Arc::new(path.clone()))),
+            Self::Field { key, comparison, value } => {
+                let lhs = format!("({} -> ${})", column, bind(Arc::new(key.clone())));
+                let value: Arc<dyn ToSql + Sync> = Arc::new(value.clone());
+                compare_sql(&lhs, *comparison, &value, params)
             }
-
-            // LIMIT clause
-            if let Some(limit) = self.limit {
-                query.push_str(&format!(" LIMIT {}", limit));
+            Self::FieldText { key, comparison, value } => {
+                let lhs = format!("({} ->> ${})", column, bind(Arc::new(key.clone())));
+                compare_sql(&lhs, *comparison, value, params)
+            }
+            Self::Path { path, comparison, value } => {
+                let lhs = format!("({} #> ${})", column, bind(Arc::new(path.clone())));
+                let value: Arc<dyn ToSql + Sync> = Arc::new(value.clone());
+                compare_sql(&lhs, *comparison, &value, params)
+            }
+            Self::PathText { path, comparison, value } => {
+                let lhs = format!("({} #>> ${})", column, bind(Arc::new(path.clone())));
+                compare_sql(&lhs, *comparison, value, params)
+            }
+            Self::FieldNull { key, not_null } => {
+                format!("({} ->> ${}) {}", column, bind(Arc::new(key.clone())), null_operator(*not_null))
+            }
+            Self::PathNull { path, not_null } => {
+                format!("({} #>> ${}) {}", column, bind(Arc::new(path.clone())), null_operator(*not_null))
             }
         }
-
-        ( query , params) 
     }
 }
 
 
+fn null_operator(not_null: bool) -> &'static str {
+    if not_null {
+        "IS NOT NULL"
+    } else {
+        "IS NULL"
+    }
+}
 
 
-#[derive(PartialEq,Default)]
+#[allow(non_camel_case_types)]
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
 pub enum ComparisonType {
     #[default]
     EQ,
@@ -228,8 +1607,23 @@ pub enum ComparisonType {
     LTE,
     GTE,
     LIKE,
+    // The parameter is an array: column = ANY($1)
     IN,
-    NULL
+    // The parameter is an array: column <> ALL($1)
+    NOT_IN,
+    NULL,
+    NE,
+    NOT_NULL,
+    NOT_LIKE,
+    ILIKE,
+    NOT_ILIKE,
+    // POSIX regular expression, case sensitive
+    REGEX,
+    // POSIX regular expression, case insensitive
+    IREGEX,
+    // Like NE, but NULL counts as a value: NULL is distinct from 1 and not from NULL
+    DISTINCT_FROM,
+    NOT_DISTINCT_FROM
 }
 
 impl ComparisonType {
@@ -241,17 +1635,40 @@ impl ComparisonType {
             Self::LTE => "<=",
             Self::GTE => ">=",
             Self::LIKE => "LIKE",
-            Self::IN => "IN",
+            Self::IN => "= ANY",
+            Self::NOT_IN => "<> ALL",
             Self::NULL => "IS NULL",
+            Self::NE => "<>",
+            Self::NOT_NULL => "IS NOT NULL",
+            Self::NOT_LIKE => "NOT LIKE",
+            Self::ILIKE => "ILIKE",
+            Self::NOT_ILIKE => "NOT ILIKE",
+            Self::REGEX => "~",
+            Self::IREGEX => "~*",
+            Self::DISTINCT_FROM => "IS DISTINCT FROM",
+            Self::NOT_DISTINCT_FROM => "IS NOT DISTINCT FROM",
         }
     }
+
+    pub fn takes_param(&self) -> bool {
+        !matches!(self, Self::NULL | Self::NOT_NULL)
+    }
+
+    pub fn takes_array(&self) -> bool {
+        matches!(self, Self::IN | Self::NOT_IN)
+    }
 }
 
 
+#[derive(Default)]
 pub enum SqlStatementBase {
+	#[default]
 	SelectAll,
     SelectCountAll,
-    Delete
+    Delete,
+    Insert,
+    Update,
+    Select(SelectList)
 }
 
 impl SqlStatementBase {
@@ -260,15 +1677,154 @@ impl SqlStatementBase {
 
 		match self {
 
-			Self::SelectAll => "SELECT *" ,
-            Self::SelectCountAll => "SELECT COUNT(*)" ,
-            Self::Delete => "DELETE"
+			Self::Delete => "DELETE".to_string(),
+            Self::Insert => "INSERT INTO".to_string(),
+            Self::Update => "UPDATE".to_string(),
+            _ => self.select_list().map(|list| list.build()).unwrap_or_default()
 
-		}.to_string() 
+		}
+	}
+
+	// SelectAll and SelectCountAll are shorthands for these lists
+	pub fn select_list(&self) -> Option<SelectList> {
+		match self {
+			Self::SelectAll => Some(SelectList::default()),
+			Self::SelectCountAll => Some(SelectList {
+				distinct: false,
+				columns: vec![SelectExpr::count_all().into()],
+			}),
+			Self::Select(list) => Some(list.clone()),
+			Self::Delete | Self::Insert | Self::Update => None,
+		}
 	}
 
 }
 
+
+// The part of a SELECT before FROM. No columns selects *
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelectList {
+    pub distinct: bool,
+    pub columns: Vec<SelectColumn>,
+}
+
+impl SelectList {
+    pub fn build(&self) -> String {
+        let columns = if self.columns.is_empty() {
+            "*".to_string()
+        } else {
+            self.columns.iter().map(SelectColumn::build).collect::<Vec<_>>().join(", ")
+        };
+
+        if self.distinct {
+            format!("SELECT DISTINCT {}", columns)
+        } else {
+            format!("SELECT {}", columns)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectColumn {
+    pub expr: SelectExpr,
+    pub alias: Option<TinySafeString>,
+}
+
+impl SelectColumn {
+    pub fn build(&self) -> String {
+        match &self.alias {
+            Some(alias) => format!("{} AS {}", self.expr.build(), alias),
+            None => self.expr.build(),
+        }
+    }
+}
+
+impl From<SelectExpr> for SelectColumn {
+    fn from(expr: SelectExpr) -> Self {
+        SelectColumn { expr, alias: None }
+    }
+}
+
+impl From<&str> for SelectColumn {
+    fn from(column: &str) -> Self {
+        SelectExpr::column(column).into()
+    }
+}
+
+// Identifiers are TinySafeStrings and literals are integers, so nothing here can carry SQL in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectExpr {
+    // *
+    All,
+    Column(TinySafeString),
+    Integer(i64),
+    Count(Box<SelectExpr>),
+    CountDistinct(Box<SelectExpr>),
+    Sum(Box<SelectExpr>),
+    Min(Box<SelectExpr>),
+    Max(Box<SelectExpr>),
+    Coalesce(Vec<SelectExpr>),
+}
+
+impl SelectExpr {
+    pub fn column(column: impl Into<TinySafeString>) -> Self {
+        Self::Column(column.into())
+    }
+
+    pub fn count_all() -> Self {
+        Self::Count(Box::new(Self::All))
+    }
+
+    pub fn count(column: impl Into<TinySafeString>) -> Self {
+        Self::Count(Box::new(Self::column(column)))
+    }
+
+    pub fn count_distinct(column: impl Into<TinySafeString>) -> Self {
+        Self::CountDistinct(Box::new(Self::column(column)))
+    }
+
+    pub fn sum(column: impl Into<TinySafeString>) -> Self {
+        Self::Sum(Box::new(Self::column(column)))
+    }
+
+    pub fn min(column: impl Into<TinySafeString>) -> Self {
+        Self::Min(Box::new(Self::column(column)))
+    }
+
+    pub fn max(column: impl Into<TinySafeString>) -> Self {
+        Self::Max(Box::new(Self::column(column)))
+    }
+
+    // e.g. coalesce([SelectExpr::sum("amount"), SelectExpr::Integer(0)])
+    pub fn coalesce(exprs: impl IntoIterator<Item = SelectExpr>) -> Self {
+        Self::Coalesce(exprs.into_iter().collect())
+    }
+
+    pub fn alias(self, alias: impl Into<TinySafeString>) -> SelectColumn {
+        SelectColumn {
+            expr: self,
+            alias: Some(alias.into()),
+        }
+    }
+
+    pub fn build(&self) -> String {
+        match self {
+            Self::All => "*".to_string(),
+            Self::Column(column) => column.to_string(),
+            Self::Integer(value) => value.to_string(),
+            Self::Count(expr) => format!("COUNT({})", expr.build()),
+            Self::CountDistinct(expr) => format!("COUNT(DISTINCT {})", expr.build()),
+            Self::Sum(expr) => format!("SUM({})", expr.build()),
+            Self::Min(expr) => format!("MIN({})", expr.build()),
+            Self::Max(expr) => format!("MAX({})", expr.build()),
+            Self::Coalesce(exprs) => format!(
+                "COALESCE({})",
+                exprs.iter().map(SelectExpr::build).collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
 pub enum OrderingDirection {
 
 	DESC,
@@ -298,6 +1854,7 @@ impl OrderingDirection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::postgres::test_support::test_database;
     use std::collections::BTreeMap;
     use std::sync::Arc;
    
@@ -310,13 +1867,14 @@ mod tests {
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::SelectAll,
             table_name: "teller_bids".into(),
-            where_params,
+            where_conditions: where_params.into(),
             order: Some(("created_at".into(), OrderingDirection::DESC)),
             limit: Some(10),
             pagination: None,
+            ..Default::default()
         };
         
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build().unwrap();
         assert_eq!(
             query,
             "SELECT * FROM teller_bids WHERE chain_id = $1 AND status = $2 ORDER BY created_at DESC LIMIT 10"
@@ -334,13 +1892,14 @@ mod tests {
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::SelectAll,
             table_name: "transactions".into(),
-            where_params,
+            where_conditions: where_params.into(),
             order: None,
             limit: None,
             pagination: None,
+            ..Default::default()
         };
         
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build().unwrap();
         assert_eq!(
             query,
             "SELECT * FROM transactions WHERE amount > $1 AND created_at <= $2 AND name LIKE $3"
@@ -358,13 +1917,14 @@ mod tests {
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::SelectAll,
             table_name: "users".into(),
-            where_params,
+            where_conditions: where_params.into(),
             order: None,
             limit: None,
             pagination: None,
+            ..Default::default()
         };
         
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build().unwrap();
         assert_eq!(
             query,
             "SELECT * FROM users WHERE deleted_at IS NULL AND status = $1"
@@ -376,22 +1936,23 @@ mod tests {
     #[test]
     fn test_sql_builder_with_in_operator() {
         let mut where_params: BTreeMap<TinySafeString, (ComparisonType, Arc<dyn ToSql + Sync>)> = BTreeMap::new();
-        // For an IN condition, you'd typically pass an array value
-        where_params.insert("status".into(), (ComparisonType::IN, Arc::new("(1, 2, 3)".to_string()) as Arc<dyn ToSql + Sync>));
+        // IN takes an array parameter
+        where_params.insert("status".into(), (ComparisonType::IN, Arc::new(vec![1_i32, 2, 3]) as Arc<dyn ToSql + Sync>));
         
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::SelectCountAll,
             table_name: "orders".into(),
-            where_params,
+            where_conditions: where_params.into(),
             order: None,
             limit: None,
             pagination: None,
+            ..Default::default()
         };
         
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build().unwrap();
         assert_eq!(
             query,
-            "SELECT COUNT(*) FROM orders WHERE status IN ($1)"
+            "SELECT COUNT(*) FROM orders WHERE status = ANY($1)"
         );
         assert_eq!(params.len(), 1);
     }
@@ -411,13 +1972,14 @@ mod tests {
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::SelectAll,
             table_name: "products".into(),
-            where_params,
+            where_conditions: where_params.into(),
             order: Some(("id".into(), OrderingDirection::ASC)), // This should be overridden by pagination
             limit: Some(50), // This should be overridden by pagination
             pagination: Some(pagination),
+            ..Default::default()
         };
         
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build().unwrap();
         // The exact query depends on how the PaginationData.build_query_part() method is implemented
         assert!(query.contains("FROM products WHERE active = $1"));
         assert_eq!(params.len(), 1);
@@ -431,17 +1993,619 @@ mod tests {
         let sql_builder = SqlBuilder {
             statement_base: SqlStatementBase::Delete,
             table_name: "logs".into(),
-            where_params,
+            where_conditions: where_params.into(),
             order: None,
             limit: None,
             pagination: None,
+            ..Default::default()
         };
         
-        let (query, params) = sql_builder.build();
+        let (query, params) = sql_builder.build().unwrap();
         assert_eq!(
             query,
             "DELETE FROM logs WHERE id = $1"
         );
         assert_eq!(params.len(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_condition_tree() {
+        let sql_builder = SqlBuilder::new(SqlStatementBase::SelectAll, "invoices")
+            .where_eq("chain_id", 1_i64)
+            .where_or([
+                Condition::eq("status", "paid".to_string()),
+                Condition::and([
+                    Condition::eq("status", "refunded".to_string()),
+                    !Condition::null("refunded_at"),
+                ]),
+            ])
+            .where_condition(!Condition::or([
+                Condition::lt("amount", 10_i64),
+                Condition::gte("amount", 1000_i64),
+            ]))
+            .order_by("created_at", OrderingDirection::DESC);
+
+        let (query, params) = sql_builder.build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM invoices WHERE chain_id = $1 \
+             AND (status = $2 OR (status = $3 AND NOT (refunded_at IS NULL))) \
+             AND NOT (amount < $4 OR amount >= $5) ORDER BY created_at DESC"
+        );
+        assert_eq!(params.len(), 5);
+
+        // A single condition needs no parentheses, an empty OR matches nothing
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "invoices")
+            .where_or([Condition::eq("status", "paid".to_string())])
+            .where_or([])
+            .build().unwrap();
+        assert_eq!(query, "SELECT * FROM invoices WHERE status = $1 AND FALSE");
+        assert_eq!(params.len(), 1);
+
+        // Empty groups don't count as a filter
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "invoices").where_condition(Condition::and([]));
+        assert!(delete.where_conditions.is_empty());
+        assert!(delete.build().is_err());
+    }
+
+    #[test]
+    fn test_repeated_columns_and_ranges() {
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_gte("created_at", 100_i64)
+            .where_lt("created_at", 200_i64)
+            .where_eq("chain_id", 1_i64)
+            .where_between("log_index", 0_i32, 10_i32)
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM events WHERE created_at >= $1 AND created_at < $2 AND chain_id = $3 \
+             AND log_index BETWEEN $4 AND $5"
+        );
+        assert_eq!(params.len(), 5);
+
+        let range_query = |condition: Condition| {
+            SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+                .where_condition(condition)
+                .build()
+                .unwrap()
+                .0
+        };
+        assert_eq!(
+            range_query(Condition::range("block_number", 10_i64..20)),
+            "SELECT * FROM events WHERE block_number >= $1 AND block_number < $2"
+        );
+        assert_eq!(
+            range_query(Condition::range("block_number", 10_i64..=20)),
+            "SELECT * FROM events WHERE block_number >= $1 AND block_number <= $2"
+        );
+        assert_eq!(
+            range_query(Condition::range("block_number", ..20_i64)),
+            "SELECT * FROM events WHERE block_number < $1"
+        );
+        assert_eq!(
+            range_query(Condition::range::<i64>("block_number", ..)),
+            "SELECT * FROM events"
+        );
+
+        // Inside an OR the range keeps its own parentheses
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_range("block_number", 10_i64..20)
+            .where_or([Condition::range("block_number", 100_i64..), Condition::null("block_number")])
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM events WHERE block_number >= $1 AND block_number < $2 \
+             AND (block_number >= $3 OR block_number IS NULL)"
+        );
+        assert_eq!(params.len(), 3);
+    }
+
+    #[test]
+    fn test_in_and_not_in() {
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "orders")
+            .where_in("status", vec!["paid".to_string(), "refunded".to_string()])
+            .where_not_in("id", vec![1_i64, 2, 3])
+            .build().unwrap();
+        assert_eq!(query, "SELECT * FROM orders WHERE status = ANY($1) AND id <> ALL($2)");
+        assert_eq!(params.len(), 2);
+
+        // Nothing is in an empty list
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "orders")
+            .where_in("id", Vec::<i64>::new())
+            .build().unwrap();
+        assert_eq!(query, "SELECT * FROM orders WHERE FALSE");
+        assert!(params.is_empty());
+
+        // Everything is outside of it
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "orders")
+            .where_not_in("id", Vec::<i64>::new())
+            .where_eq("chain_id", 1_i64)
+            .build().unwrap();
+        assert_eq!(query, "SELECT * FROM orders WHERE chain_id = $1");
+        assert_eq!(params.len(), 1);
+
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "orders").where_not_in("id", Vec::<i64>::new());
+        assert!(delete.build().is_err());
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "orders").where_or([Condition::not_in("id", Vec::<i64>::new())]);
+        assert!(delete.build().is_err());
+        let update = SqlBuilder::new(SqlStatementBase::Update, "orders")
+            .value("status", "void".to_string())
+            .where_or([Condition::eq("chain_id", 1_i64), Condition::not_in("id", Vec::<i64>::new())]);
+        assert!(update.build().is_err());
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "orders").where_in("id", Vec::<i64>::new());
+        assert_eq!(delete.build().unwrap().0, "DELETE FROM orders WHERE FALSE");
+    }
+
+    #[test]
+    fn test_more_comparison_operators() {
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "users")
+            .where_ne("status", "banned".to_string())
+            .where_not_null("email")
+            .where_not_like("email", "%@example.com".to_string())
+            .where_ilike("name", "al%".to_string())
+            .where_not_ilike("name", "%bot%".to_string())
+            .where_regex("handle", "^[a-z]+$".to_string())
+            .where_iregex("handle", "^admin".to_string())
+            .where_distinct_from("referrer_id", Some(7_i64))
+            .where_not_distinct_from("deleted_by", None::<i64>)
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM users WHERE status <> $1 AND email IS NOT NULL AND email NOT LIKE $2 \
+             AND name ILIKE $3 AND name NOT ILIKE $4 AND handle ~ $5 AND handle ~* $6 \
+             AND referrer_id IS DISTINCT FROM $7 AND deleted_by IS NOT DISTINCT FROM $8"
+        );
+        assert_eq!(params.len(), 8);
+
+        assert!(!ComparisonType::NOT_NULL.takes_param());
+        assert!(ComparisonType::NE.takes_param());
+    }
+
+    #[test]
+    fn test_like_pattern_escaping() {
+        assert_eq!(escape_like_pattern("50%_off\\"), "50\\%\\_off\\\\");
+        assert_eq!(escape_like_pattern("plain"), "plain");
+
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "products")
+            .where_contains("name", "50%")
+            .where_starts_with("sku", "A_")
+            .where_ends_with("slug", "-sale")
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM products WHERE name LIKE $1 AND sku LIKE $2 AND slug LIKE $3"
+        );
+        let patterns: Vec<String> = params.iter().map(|param| format!("{:?}", param)).collect();
+        assert_eq!(patterns, ["\"%50\\\\%%\"", "\"A\\\\_%\"", "\"%-sale\""]);
+    }
+
+    #[test]
+    fn test_json_conditions() {
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_json_contains("args", serde_json::json!({ "from": "0xabc" }))
+            .where_json_has_key("data", "amount")
+            .where_json_has_any_key("data", vec!["fee".into(), "tip".into()])
+            .where_json_has_all_keys("data", vec!["from".into(), "to".into()])
+            .where_json_field("data", "amount", ComparisonType::GT, serde_json::json!(1000))
+            .where_json_field_text("data", "currency", ComparisonType::IN, vec!["usd".to_string(), "eur".to_string()])
+            .where_json_path("args", vec!["payer".into(), "score".into()], ComparisonType::LTE, serde_json::json!(5))
+            .where_json_path_not_null("args", vec!["payer".into(), "memo".into()])
+            .where_jsonpath("data", "$.items[*] ? (@.price > 10)")
+            .build().unwrap();
+
+        assert_eq!(
+            query,
+            "SELECT * FROM events WHERE args @> $1 AND data ? $2 AND data ?| $3 AND data ?& $4 \
+             AND (data -> $5) > $6 AND (data ->> $7) = ANY($8) AND (args #> $9) <= $10 \
+             AND (args #>> $11) IS NOT NULL AND data @? $12::text::jsonpath"
+        );
+        assert_eq!(params.len(), 12);
+
+        // Keys are parameters, so they can't break out of the query
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_json_field_text("data", "x' OR '1'='1", ComparisonType::EQ, "y".to_string())
+            .build().unwrap();
+        assert_eq!(query, "SELECT * FROM events WHERE (data ->> $1) = $2");
+        assert_eq!(format!("{:?}", params[0]), "\"x' OR '1'='1\"");
+
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_json_field_null("data", "refunded_at")
+            .where_json_path_null("args", vec!["payer".into(), "memo".into()])
+            .where_json_field_not_null("data", "amount")
+            .where_json_path_not_null("args", vec!["payer".into(), "address".into()])
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT * FROM events WHERE (data ->> $1) IS NULL AND (args #>> $2) IS NULL AND (data ->> $3) IS NOT NULL \
+             AND (args #>> $4) IS NOT NULL"
+        );
+        assert_eq!(params.len(), 4);
+
+        // A jsonb value can't be compared against an array parameter, nested or not
+        let field_in = SqlBuilder::new(SqlStatementBase::SelectAll, "events")
+            .where_json_field("data", "currency", ComparisonType::IN, serde_json::json!(["usd", "eur"]));
+        assert!(matches!(
+            field_in.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+
+        let nested_path_not_in = SqlBuilder::new(SqlStatementBase::SelectAll, "events").where_or([
+            Condition::eq("chain_id", 1_i64),
+            Condition::json(
+                "args",
+                JsonCondition::Path {
+                    path: vec!["payer".into()],
+                    comparison: ComparisonType::NOT_IN,
+                    value: serde_json::json!(["0xabc"]),
+                },
+            ),
+        ]);
+        assert!(nested_path_not_in.build().is_err());
+    }
+
+    #[test]
+    fn test_select_columns() {
+        let (query, params) = SqlBuilder::new(SqlStatementBase::SelectAll, "payments")
+            .column("id")
+            .select(SelectExpr::column("payer_address").alias("payer"))
+            .where_eq("chain_id", 1_i64)
+            .order_by("payer", OrderingDirection::ASC)
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT id, payer_address AS payer FROM payments WHERE chain_id = $1 ORDER BY payer ASC"
+        );
+        assert_eq!(params.len(), 1);
+
+        let (query, _) = SqlBuilder::new(SqlStatementBase::SelectAll, "payments")
+            .select(SelectExpr::count_distinct("payer_address").alias("payers"))
+            .select(SelectExpr::coalesce([SelectExpr::sum("amount"), SelectExpr::Integer(0)]).alias("total"))
+            .select(SelectExpr::min("created_at"))
+            .select(SelectExpr::max("created_at"))
+            .select(SelectExpr::count("refunded_at"))
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "SELECT COUNT(DISTINCT payer_address) AS payers, COALESCE(SUM(amount), 0) AS total, \
+             MIN(created_at), MAX(created_at), COUNT(refunded_at) FROM payments"
+        );
+
+        let (query, _) = SqlBuilder::new(SqlStatementBase::SelectAll, "payments")
+            .distinct()
+            .column("payer_address")
+            .build().unwrap();
+        assert_eq!(query, "SELECT DISTINCT payer_address FROM payments");
+
+        // Without columns DISTINCT applies to *
+        let (query, _) = SqlBuilder::new(SqlStatementBase::SelectAll, "payments").distinct().build().unwrap();
+        assert_eq!(query, "SELECT DISTINCT * FROM payments");
+
+        // A DELETE stays a DELETE and is refused rather than turned into a SELECT
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "payments")
+            .column("id")
+            .where_eq("id", 1_i64);
+        assert!(matches!(delete.statement_base, SqlStatementBase::Delete));
+        assert!(matches!(
+            delete.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+    }
+
+    #[test]
+    fn test_select_count_all_is_a_select_list() {
+        let count_all = SqlStatementBase::SelectCountAll.select_list().unwrap();
+        assert_eq!(count_all.columns, vec![SelectColumn::from(SelectExpr::count_all())]);
+        assert_eq!(count_all.build(), SqlStatementBase::SelectCountAll.build());
+        assert_eq!(SqlStatementBase::SelectAll.build(), "SELECT *");
+        assert!(SqlStatementBase::Delete.select_list().is_none());
+
+        let (query, _) = SqlBuilder::new(SqlStatementBase::SelectCountAll, "payments")
+            .select(SelectExpr::sum("amount").alias("total"))
+            .build().unwrap();
+        assert_eq!(query, "SELECT COUNT(*), SUM(amount) AS total FROM payments");
+    }
+
+    #[test]
+    fn test_insert_statement() {
+        let sql_builder = SqlBuilder::new(SqlStatementBase::Insert, "events")
+            .value("contract_address", "0xabc".to_string())
+            .value("chain_id", 1_i64)
+            .value_default("created_at")
+            .value("block_number", 100_i64)
+            .returning("id")
+            .returning("created_at");
+
+        let (query, params) = sql_builder.build().unwrap();
+        assert_eq!(
+            query,
+            "INSERT INTO events (block_number, chain_id, contract_address, created_at) VALUES ($1, $2, $3, DEFAULT) RETURNING id, created_at"
+        );
+        assert_eq!(params.len(), 3);
+
+        let (query, params) = SqlBuilder::new(SqlStatementBase::Insert, "events").build().unwrap();
+        assert_eq!(query, "INSERT INTO events DEFAULT VALUES");
+        assert!(params.is_empty());
+
+        let filtered = SqlBuilder::new(SqlStatementBase::Insert, "events")
+            .value("chain_id", 1_i64)
+            .where_eq("chain_id", 1_i64);
+        assert!(matches!(
+            filtered.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+    }
+
+    #[test]
+    fn test_update_statement() {
+        let sql_builder = SqlBuilder::new(SqlStatementBase::Update, "accounts")
+            .value("status", "active".to_string())
+            .value_increment("login_count", 1_i32)
+            .value_now("updated_at")
+            .where_eq("id", 42_i64)
+            .where_null("deleted_at")
+            .where_lt("version", 3_i32)
+            .returning("login_count");
+
+        let (query, params) = sql_builder.build().unwrap();
+        assert_eq!(
+            query,
+            "UPDATE accounts SET login_count = login_count + $1, status = $2, updated_at = NOW() \
+             WHERE id = $3 AND deleted_at IS NULL AND version < $4 RETURNING login_count"
+        );
+        assert_eq!(params.len(), 4);
+    }
+
+    #[test]
+    fn test_unfiltered_statements_are_refused() {
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "logs");
+        assert!(matches!(
+            delete.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+        let (query, _) = delete.allow_unfiltered().returning("id").build().unwrap();
+        assert_eq!(query, "DELETE FROM logs RETURNING id");
+
+        let update = SqlBuilder::new(SqlStatementBase::Update, "logs").value("archived", true);
+        assert!(update.build().is_err());
+        let (query, params) = update.allow_unfiltered().build().unwrap();
+        assert_eq!(query, "UPDATE logs SET archived = $1");
+        assert_eq!(params.len(), 1);
+
+        // Nothing to set
+        let update = SqlBuilder::new(SqlStatementBase::Update, "logs").where_eq("id", 1_i64);
+        assert!(update.build().is_err());
+
+        // Increments need an existing row
+        let insert = SqlBuilder::new(SqlStatementBase::Insert, "logs").value_increment("count", 1_i32);
+        assert!(insert.build().is_err());
+
+        // Conditions that match every row don't count as a filter, however they are nested
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "logs")
+            .where_or([Condition::eq("id", 1_i64), Condition::range::<i64>("id", ..)]);
+        assert!(delete.build().is_err());
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "logs").where_or([Condition::range::<i64>("id", ..)]);
+        assert!(delete.build().is_err());
+        let update = SqlBuilder::new(SqlStatementBase::Update, "logs")
+            .value("archived", true)
+            .where_condition(Condition::and([Condition::or([Condition::and([])])]));
+        assert!(update.build().is_err());
+        let delete = SqlBuilder::new(SqlStatementBase::Delete, "logs").where_condition(!Condition::is_in("id", Vec::<i64>::new()));
+        assert!(delete.build().is_err());
+
+        // An OR of conditions that all filter is still a filter
+        let (query, _) = SqlBuilder::new(SqlStatementBase::Delete, "logs")
+            .where_or([Condition::eq("id", 1_i64), Condition::and([Condition::range::<i64>("id", ..), Condition::eq("id", 2_i64)])])
+            .build()
+            .unwrap();
+        assert_eq!(query, "DELETE FROM logs WHERE (id = $1 OR id = $2)");
+    }
+
+    #[test]
+    fn test_upsert_statement() {
+        let sql_builder = SqlBuilder::new(SqlStatementBase::Insert, "premium_status")
+            .value("owner_address", "0xabc".to_string())
+            .value("chain_id", 1_i64)
+            .value("expires_at", 1000_i64)
+            .on_conflict_do_update(ConflictTarget::columns(["owner_address", "chain_id"]), ["expires_at"])
+            .on_conflict_set("renewals", SqlValue::Increment(Arc::new(1_i32)))
+            .where_lt("expires_at", 1000_i64)
+            .returning("id");
+
+        let (query, params) = sql_builder.build().unwrap();
+        assert_eq!(
+            query,
+            "INSERT INTO premium_status (chain_id, expires_at, owner_address) VALUES ($1, $2, $3) \
+             ON CONFLICT (owner_address, chain_id) DO UPDATE SET expires_at = EXCLUDED.expires_at, \
+             renewals = premium_status.renewals + $4 WHERE premium_status.expires_at < $5 \
+             RETURNING id, (xmax = 0) AS upsert_inserted"
+        );
+        assert_eq!(params.len(), 5);
+
+        let (query, params) = SqlBuilder::new(SqlStatementBase::Insert, "webhook_urls")
+            .value("url", "https://example.com".to_string())
+            .on_conflict_do_nothing(ConflictTarget::Constraint("webhook_urls_url_key".into()))
+            .build().unwrap();
+        assert_eq!(
+            query,
+            "INSERT INTO webhook_urls (url) VALUES ($1) ON CONFLICT ON CONSTRAINT webhook_urls_url_key DO NOTHING \
+             RETURNING (xmax = 0) AS upsert_inserted"
+        );
+        assert_eq!(params.len(), 1);
+
+        // DO UPDATE needs a conflict target and something to set
+        let any_target = SqlBuilder::new(SqlStatementBase::Insert, "webhook_urls")
+            .value("url", "https://example.com".to_string())
+            .on_conflict_do_update(ConflictTarget::Any, ["url"]);
+        assert!(any_target.build().is_err());
+
+        let nothing_to_set = SqlBuilder::new(SqlStatementBase::Insert, "webhook_urls")
+            .value("url", "https://example.com".to_string())
+            .on_conflict_do_update(ConflictTarget::columns(["url"]), Vec::<TinySafeString>::new());
+        assert!(nothing_to_set.build().is_err());
+
+        // Nothing would use the conditions or the assignment
+        let filtered_do_nothing = SqlBuilder::new(SqlStatementBase::Insert, "webhook_urls")
+            .value("url", "https://example.com".to_string())
+            .on_conflict_do_nothing(ConflictTarget::columns(["url"]))
+            .where_eq("active", true);
+        assert!(matches!(
+            filtered_do_nothing.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+
+        let set_too_early = SqlBuilder::new(SqlStatementBase::Insert, "webhook_urls")
+            .value("url", "https://example.com".to_string())
+            .on_conflict_set("hits", SqlValue::Increment(Arc::new(1_i32)))
+            .on_conflict_do_update(ConflictTarget::columns(["url"]), ["url"]);
+        assert!(matches!(
+            set_too_early.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+    }
+
+    #[test]
+    fn test_batch_insert_chunks() {
+        let mut batch = BatchInsert::new("events", ["chain_id", "block_number"]);
+        for block_number in 0..5_i64 {
+            batch.push_row(vec![Arc::new(1_i64), Arc::new(block_number)]);
+        }
+
+        let statements = batch.build().unwrap();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(
+            statements[0].0,
+            "INSERT INTO events (chain_id, block_number) VALUES ($1, $2), ($3, $4), ($5, $6), ($7, $8), ($9, $10)"
+        );
+        assert_eq!(statements[0].1.len(), 10);
+
+        // 5 params allow 2 rows per statement
+        batch.max_params_per_statement = 5;
+        let statements = batch.build().unwrap();
+        let queries: Vec<&str> = statements.iter().map(|(query, _)| query.as_str()).collect();
+        assert_eq!(
+            queries,
+            [
+                "INSERT INTO events (chain_id, block_number) VALUES ($1, $2), ($3, $4)",
+                "INSERT INTO events (chain_id, block_number) VALUES ($1, $2), ($3, $4)",
+                "INSERT INTO events (chain_id, block_number) VALUES ($1, $2)",
+            ]
+        );
+
+        // The protocol limit applies even when a higher one is configured
+        let mut wide = BatchInsert::new("events", ["a", "b", "c"]);
+        wide.max_params_per_statement = usize::MAX;
+        wide.rows = (0..30_000).map(|_| vec![Arc::new(1_i32) as Arc<dyn ToSql + Sync>; 3]).collect();
+        let statements = wide.build().unwrap();
+        assert_eq!(statements.len(), 3);
+        assert!(statements.iter().all(|(_, params)| params.len() <= MAX_BIND_PARAMS));
+
+        assert!(BatchInsert::new("events", ["a"]).build().unwrap().is_empty());
+        assert!(BatchInsert::new("events", Vec::<TinySafeString>::new()).build().is_err());
+
+        let too_wide = BatchInsert::new("events", (0..=MAX_BIND_PARAMS).map(|i| TinySafeString::new(&format!("c{}", i)).unwrap()))
+            .row((0..=MAX_BIND_PARAMS).map(|_| Arc::new(1_i32) as Arc<dyn ToSql + Sync>).collect());
+        assert!(matches!(
+            too_wide.build(),
+            Err(PostgresModelError::InvalidStatement(_))
+        ));
+
+        let mut narrow_limit = BatchInsert::new("events", ["a", "b"]).row(vec![Arc::new(1_i32), Arc::new(2_i32)]);
+        narrow_limit.max_params_per_statement = 1;
+        assert!(narrow_limit.build().is_err());
+        assert!(BatchInsert::new("events", ["a", "b"])
+            .row(vec![Arc::new(1_i32)])
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_insert_returning_id() {
+        let database = test_database();
+
+        database
+            .execute("CREATE TEMP TABLE insert_returning_test (id SERIAL PRIMARY KEY, name TEXT NOT NULL)", &[])
+            .await
+            .unwrap();
+
+        for expected_id in 1..=2 {
+            let (query, params) = SqlBuilder::new(SqlStatementBase::Insert, "insert_returning_test")
+                .value("name", format!("row {}", expected_id))
+                .returning("id")
+                .build().unwrap();
+            let built_params = params.iter().map(|x| &**x).collect::<Vec<_>>();
+
+            let row = database.query_one(&query, &built_params).await.unwrap();
+            let id: i32 = row.get("id");
+            assert_eq!(id, expected_id);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_upsert_outcome() {
+        let database = test_database();
+
+        database
+            .execute("CREATE TEMP TABLE upsert_test (name TEXT PRIMARY KEY, hits INT NOT NULL)", &[])
+            .await
+            .unwrap();
+
+        let upsert = |hits: i32| {
+            SqlBuilder::new(SqlStatementBase::Insert, "upsert_test")
+                .value("name", "a".to_string())
+                .value("hits", hits)
+                .on_conflict_do_update(ConflictTarget::columns(["name"]), Vec::<TinySafeString>::new())
+                .on_conflict_set("hits", SqlValue::Increment(Arc::new(hits)))
+                .where_lt("hits", 10_i32)
+                .returning("hits")
+        };
+
+        let outcome = database.upsert_sql_builder(&upsert(5)).await.unwrap();
+        assert!(matches!(&outcome, UpsertOutcome::Inserted(row) if row.get::<_, i32>("hits") == 5));
+
+        let outcome = database.upsert_sql_builder(&upsert(5)).await.unwrap();
+        assert!(matches!(&outcome, UpsertOutcome::Updated(row) if row.get::<_, i32>("hits") == 10));
+
+        // hits is no longer below 10
+        let outcome = database.upsert_sql_builder(&upsert(5)).await.unwrap();
+        assert!(matches!(outcome, UpsertOutcome::Skipped));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database at DATABASE_URL"]
+    async fn test_execute_batch_insert() {
+        let database = test_database();
+
+        database
+            .execute("CREATE TEMP TABLE batch_insert_test (id INT PRIMARY KEY, name TEXT NOT NULL)", &[])
+            .await
+            .unwrap();
+
+        let count = || async {
+            let row = database.query_one("SELECT COUNT(*) FROM batch_insert_test", &[]).await.unwrap();
+            row.get::<_, i64>(0)
+        };
+
+        // 4 params allow 2 rows per statement, so 5 rows take 3 statements
+        let mut batch = BatchInsert::new("batch_insert_test", ["id", "name"]);
+        batch.max_params_per_statement = 4;
+        for id in 1..=5_i32 {
+            batch.push_row(vec![Arc::new(id), Arc::new(format!("row {}", id))]);
+        }
+        assert_eq!(batch.build().unwrap().len(), 3);
+
+        assert_eq!(database.execute_batch_insert(&batch).await.unwrap(), 5);
+        assert_eq!(count().await, 5);
+
+        // The first two statements succeed, the third hits the existing id 1
+        let mut failing = BatchInsert::new("batch_insert_test", ["id", "name"]);
+        failing.max_params_per_statement = 4;
+        for id in [6, 7, 8, 9, 1_i32] {
+            failing.push_row(vec![Arc::new(id), Arc::new(format!("row {}", id))]);
+        }
+
+        assert!(database.execute_batch_insert(&failing).await.is_err());
+        assert_eq!(count().await, 5);
+    }
+}
@@ -139,6 +139,7 @@ use super::*;
     use serde_json::json;
 
     #[test]
+    #[allow(clippy::unnecessary_fallible_conversions)]
     fn test_valid_strings() {
         assert!(TryInto::<TinySafeString>::try_into("hello").is_ok() );
         assert!(TinySafeString::new("hello123").is_ok());